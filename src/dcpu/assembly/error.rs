@@ -0,0 +1,96 @@
+#![allow(dead_code)]
+use std::error::Error;
+use std::fmt::{self, Display};
+
+/// byte range of the source
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssembleError {
+    pub message: String,
+    pub file: Option<String>,   // none for sources not read from a file
+    pub span: Span,
+    pub line: usize,        // starting from 1
+    pub column: usize,      // starting from 1
+    pub snippet: String,    // source line with a caret under the span
+    pub called_from: Option<Box<AssembleError>> // macro call the error is expanded from
+}
+
+impl AssembleError {
+    pub fn new(source: &str, span: Span, message: String) -> AssembleError {
+        let line_start = source[..span.start].rfind('\n').map_or(0, |n| n + 1);
+        let line_end = source[span.start..].find('\n').map_or(source.len(), |n| span.start + n);
+        let text = source[line_start..line_end].trim_end_matches('\r');
+        let column = source[line_start..span.start].chars().count() + 1;
+        let width = source[span.start..span.end.min(line_end)].chars().count().max(1);
+
+        let indent: String = source[line_start..span.start].chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let snippet = format!("{}\n{}{}", text, indent, "^".repeat(width));
+
+        AssembleError {
+            message,
+            file: None,
+            span,
+            line: source[..span.start].matches('\n').count() + 1,
+            column,
+            snippet,
+            called_from: None
+        }
+    }
+
+    pub fn with_file(mut self, file: Option<String>) -> AssembleError {
+        self.file = file;
+        self
+    }
+}
+
+impl Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:{}: {}\n{}", self.line, self.column, self.message, self.snippet)?;
+        match self.called_from {
+            Some(ref call) => write!(f, "\n{}", call),
+            None => Ok(())
+        }
+    }
+}
+
+impl Error for AssembleError {}
+
+#[test]
+fn test_error() {
+    let source = "SET A, 1\n  SET B, foo\n";
+    let err = AssembleError::new(source, Span { start: 18, end: 21 }, "label `foo` is not defined".to_string());
+    assert_eq!(err.line, 2);
+    assert_eq!(err.column, 10);
+    assert_eq!(err.snippet, "  SET B, foo\n         ^^^");
+    assert_eq!(format!("{}", err), "2:10: label `foo` is not defined\n  SET B, foo\n         ^^^");
+    let err = err.with_file(Some("main.dasm".to_string()));
+    assert_eq!(format!("{}", err), "main.dasm:2:10: label `foo` is not defined\n  SET B, foo\n         ^^^");
+}
+
+#[test]
+fn test_error_at_end() {
+    let source = "SET A,";
+    let err = AssembleError::new(source, Span { start: 6, end: 6 }, "expected value".to_string());
+    assert_eq!(err.line, 1);
+    assert_eq!(err.column, 7);
+    assert_eq!(err.snippet, "SET A,\n      ^");
+}
+
+#[test]
+fn test_error_in_macro() {
+    let call = AssembleError::new("clear(1)", Span { start: 0, end: 8 }, "in expansion of macro `clear`".to_string());
+    let mut err = AssembleError::new("SET 1, A", Span { start: 4, end: 5 }, "expected register".to_string());
+    err.called_from = Some(Box::new(call));
+    assert_eq!(format!("{}", err), "1:5: expected register\nSET 1, A\n    ^\n\
+                                    1:1: in expansion of macro `clear`\nclear(1)\n^^^^^^^^");
+}
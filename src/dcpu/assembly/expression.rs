@@ -0,0 +1,122 @@
+#![allow(dead_code)]
+use super::tokenizer::Operator as Operator;
+use super::error::Span as Span;
+
+/// constant expression of an operand or directive, symbols are looked up
+/// once the whole source is laid out
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expression {
+    Number(u16),
+    Symbol(String, Span),
+    Unary(Operator, Box<Expression>),                   // - or ~
+    Binary(Operator, Box<Expression>, Box<Expression>)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EvalError {
+    Undefined(String, Span),
+    DivisionByZero,
+    Overflow(Option<i64>)   // result does not fit into a word, none if it is too large to compute
+}
+
+impl Expression {
+    /// true if the value does not depend on any symbol
+    pub fn is_constant(&self) -> bool {
+        match *self {
+            Expression::Number(_) => true,
+            Expression::Symbol(..) => false,
+            Expression::Unary(_, ref e) => e.is_constant(),
+            Expression::Binary(_, ref l, ref r) => l.is_constant() && r.is_constant()
+        }
+    }
+
+    /// evaluates the expression, results between -0x8000 and 0xffff are
+    /// converted to a word using two's complement
+    pub fn evaluate<F: Fn(&str) -> Option<u16>>(&self, symbols: &F) -> Result<u16, EvalError> {
+        match self.eval(symbols)? {
+            value @ -0x8000..=0xffff => Ok(value as u16),
+            value => Err(EvalError::Overflow(Some(value)))
+        }
+    }
+
+    // intermediate results are wider than a word so that `0xffff + 1 - 1` works
+    fn eval<F: Fn(&str) -> Option<u16>>(&self, symbols: &F) -> Result<i64, EvalError> {
+        match *self {
+            Expression::Number(n) => Ok(n as i64),
+            Expression::Symbol(ref name, span) => match symbols(name) {
+                Some(value) => Ok(value as i64),
+                None => Err(EvalError::Undefined(name.clone(), span))
+            },
+            Expression::Unary(op, ref e) => {
+                let value = e.eval(symbols)?;
+                match op {
+                    Operator::Sub => value.checked_neg().ok_or(EvalError::Overflow(None)),
+                    _ => Ok(!value & 0xffff)    // complement of the word
+                }
+            },
+            Expression::Binary(op, ref l, ref r) => {
+                let (l, r) = (l.eval(symbols)?, r.eval(symbols)?);
+                let result = match op {
+                    Operator::Add => l.checked_add(r),
+                    Operator::Sub => l.checked_sub(r),
+                    Operator::Mul => l.checked_mul(r),
+                    Operator::Div | Operator::Mod if r == 0 => return Err(EvalError::DivisionByZero),
+                    Operator::Div => l.checked_div(r),
+                    Operator::Mod => l.checked_rem(r),
+                    Operator::And => Some(l & r),
+                    Operator::Or => Some(l | r),
+                    Operator::Xor => Some(l ^ r),
+                    // bits shifted out at the top are lost unless shifting back restores the value
+                    Operator::Shl if (0..64).contains(&r) => Some(l << r).filter(|&v| v >> r == l),
+                    Operator::Shr if (0..64).contains(&r) => Some(l >> r),
+                    Operator::Shr if r >= 64 => Some(l >> 63),  // everything is shifted out
+                    Operator::Shl | Operator::Shr => None,      // negative or too large count
+                    Operator::Not => unreachable!()             // only unary
+                };
+                result.ok_or(EvalError::Overflow(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn number(n: u16) -> Box<Expression> {
+    Box::new(Expression::Number(n))
+}
+
+#[test]
+fn test_evaluate() {
+    let no_symbols = |_: &str| None;
+    let sum = Expression::Binary(Operator::Add, number(0xffff), number(1));
+    assert_eq!(sum.evaluate(&no_symbols), Err(EvalError::Overflow(Some(0x10000))));
+    let back = Expression::Binary(Operator::Sub, Box::new(sum), number(1));
+    assert_eq!(back.evaluate(&no_symbols), Ok(0xffff));
+    let negative = Expression::Unary(Operator::Sub, number(1));
+    assert_eq!(negative.evaluate(&no_symbols), Ok(0xffff));
+    let mask = Expression::Unary(Operator::Not, number(0xff00));
+    assert_eq!(mask.evaluate(&no_symbols), Ok(0x00ff));
+    let shift = Expression::Binary(Operator::Shl, number(1), number(100));
+    assert_eq!(shift.evaluate(&no_symbols), Err(EvalError::Overflow(None)));
+    let shift = Expression::Binary(Operator::Shl, number(1), Box::new(negative.clone()));
+    assert_eq!(shift.evaluate(&no_symbols), Err(EvalError::Overflow(None)));
+    let shift = Expression::Binary(Operator::Shr, number(0x8000), number(100));
+    assert_eq!(shift.evaluate(&no_symbols), Ok(0));
+    let mut product = Expression::Number(256);
+    for _ in 0..7 {
+        product = Expression::Binary(Operator::Mul, Box::new(product), number(256));
+    }
+    assert_eq!(product.evaluate(&no_symbols), Err(EvalError::Overflow(None)));
+    let division = Expression::Binary(Operator::Mod, number(1), number(0));
+    assert_eq!(division.evaluate(&no_symbols), Err(EvalError::DivisionByZero));
+}
+
+#[test]
+fn test_symbols() {
+    let span = Span { start: 0, end: 5 };
+    let symbols = |name: &str| if name == "label" { Some(0x10) } else { None };
+    let offset = Expression::Binary(Operator::Add, Box::new(Expression::Symbol("label".to_string(), span)), number(4));
+    assert!(!offset.is_constant());
+    assert_eq!(offset.evaluate(&symbols), Ok(0x14));
+    let missing = Expression::Symbol("other".to_string(), span);
+    assert_eq!(missing.evaluate(&symbols), Err(EvalError::Undefined("other".to_string(), span)));
+}
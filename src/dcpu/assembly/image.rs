@@ -0,0 +1,81 @@
+#![allow(dead_code)]
+
+/// words assembled to consecutive addresses, wrapping around the end of memory
+#[derive(Debug, PartialEq, Clone)]
+pub struct Segment {
+    pub origin: u16,
+    pub words: Vec<u16>
+}
+
+impl Segment {
+    pub fn new(origin: u16) -> Segment {
+        Segment {
+            origin,
+            words: vec![]
+        }
+    }
+
+    /// address right after the last word
+    pub fn end(&self) -> u16 {
+        self.origin.wrapping_add(self.words.len() as u16)
+    }
+
+    /// first address covered by both segments
+    pub fn overlap(&self, other: &Segment) -> Option<u16> {
+        if self.words.is_empty() || other.words.is_empty() {
+            None
+        } else if (other.origin.wrapping_sub(self.origin) as usize) < self.words.len() {
+            Some(other.origin)
+        } else if (self.origin.wrapping_sub(other.origin) as usize) < other.words.len() {
+            Some(self.origin)
+        } else {
+            None
+        }
+    }
+}
+
+/// assembled program, segments are kept in the order of the source
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Image {
+    pub segments: Vec<Segment>
+}
+
+impl Image {
+    /// memory contents from address 0 up to the last assembled word,
+    /// addresses not covered by any segment are zero
+    pub fn to_vec(&self) -> Vec<u16> {
+        let mut memory = vec![0u16; 0x10000];
+        let mut len = 0;
+        for segment in &self.segments {
+            for (i, &word) in segment.words.iter().enumerate() {
+                let address = segment.origin.wrapping_add(i as u16) as usize;
+                memory[address] = word;
+                len = len.max(address + 1);
+            }
+        }
+        memory.truncate(len);
+        memory
+    }
+}
+
+#[test]
+fn test_to_vec() {
+    let image = Image {
+        segments: vec![
+            Segment { origin: 2, words: vec![1, 2] },
+            Segment { origin: 0, words: vec![3] }
+        ]
+    };
+    assert_eq!(image.to_vec(), [3, 0, 1, 2]);
+    assert_eq!(image.segments[0].end(), 4);
+    assert_eq!(Image::default().to_vec(), []);
+}
+
+#[test]
+fn test_overlap() {
+    let segment = Segment { origin: 0xfffe, words: vec![1, 2, 3] };
+    assert_eq!(segment.overlap(&Segment { origin: 0, words: vec![4] }), Some(0));
+    assert_eq!(Segment { origin: 0xfff0, words: vec![5; 0x10] }.overlap(&segment), Some(0xfffe));
+    assert_eq!(segment.overlap(&Segment { origin: 1, words: vec![6] }), None);
+    assert_eq!(segment.overlap(&Segment::new(0xffff)), None);
+}
@@ -0,0 +1,159 @@
+#![allow(dead_code)]
+use std::fmt::Write;
+
+/// source line together with the words assembled from it
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListingLine {
+    pub address: u16,
+    pub words: Vec<u16>,
+    pub file: Option<String>,
+    pub line: usize,        // starting from 1
+    pub column: usize,      // of the statement, starting from 1
+    pub text: String
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>
+}
+
+/// words shown in one row of the text listing, the longest instruction fits
+const WORDS_PER_ROW: usize = 3;
+
+impl Listing {
+    /// one row per line, `address  words  file:line  source`,
+    /// words which do not fit continue on rows of their own
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for line in &self.lines {
+            let location = match line.file {
+                Some(ref file) => format!("{}:{}", file, line.line),
+                None => line.line.to_string()
+            };
+            let mut rows = line.words.chunks(WORDS_PER_ROW);
+            let words = rows.next().map_or(String::new(), format_words);
+            writeln!(text, "{:04x}  {:<14}  {}  {}", line.address, words, location, line.text).unwrap();
+            for (i, row) in rows.enumerate() {
+                let address = line.address.wrapping_add(((i + 1) * WORDS_PER_ROW) as u16);
+                writeln!(text, "{:04x}  {}", address, format_words(row)).unwrap();
+            }
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let lines: Vec<String> = self.lines.iter().map(|line| {
+            let words: Vec<String> = line.words.iter().map(|word| word.to_string()).collect();
+            format!("{{\"address\":{},\"words\":[{}],\"file\":{},\"line\":{},\"column\":{},\"text\":{}}}",
+                    line.address, words.join(","),
+                    line.file.as_ref().map_or("null".to_string(), |file| json_string(file)),
+                    line.line, line.column, json_string(&line.text))
+        }).collect();
+        format!("{{\"lines\":[{}]}}", lines.join(","))
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SymbolKind {
+    Label,
+    Constant
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u16,
+    pub kind: SymbolKind
+}
+
+/// symbols sorted by value
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SymbolMap {
+    pub symbols: Vec<Symbol>
+}
+
+impl SymbolMap {
+    pub fn new(mut symbols: Vec<Symbol>) -> SymbolMap {
+        symbols.sort_by(|a, b| (a.value, &a.name).cmp(&(b.value, &b.name)));
+        SymbolMap { symbols }
+    }
+
+    /// one symbol per line, `value  kind  name`
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for symbol in &self.symbols {
+            writeln!(text, "{:04x}  {:<8}  {}", symbol.value, kind_name(symbol.kind), symbol.name).unwrap();
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let symbols: Vec<String> = self.symbols.iter().map(|symbol| {
+            format!("{{\"name\":{},\"value\":{},\"kind\":\"{}\"}}",
+                    json_string(&symbol.name), symbol.value, kind_name(symbol.kind))
+        }).collect();
+        format!("{{\"symbols\":[{}]}}", symbols.join(","))
+    }
+}
+
+fn kind_name(kind: SymbolKind) -> &'static str {
+    match kind {
+        SymbolKind::Label => "label",
+        SymbolKind::Constant => "constant"
+    }
+}
+
+fn format_words(words: &[u16]) -> String {
+    let words: Vec<String> = words.iter().map(|word| format!("{:04x}", word)).collect();
+    words.join(" ")
+}
+
+/// quotes and escapes a string for JSON
+fn json_string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(result, "\\u{:04x}", c as u32).unwrap(),
+            c => result.push(c)
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[test]
+fn test_listing() {
+    let listing = Listing {
+        lines: vec![
+            ListingLine { address: 0, words: vec![0x7c01, 0x0020], file: None, line: 1, column: 1, text: "SET A, 32".to_string() },
+            ListingLine { address: 2, words: vec![1, 2, 3, 4], file: Some("a.dasm".to_string()), line: 2, column: 5, text: "DAT \"\\x\", 4".to_string() }
+        ]
+    };
+    assert_eq!(listing.to_text(), "0000  7c01 0020       1  SET A, 32\n\
+                                   0002  0001 0002 0003  a.dasm:2  DAT \"\\x\", 4\n\
+                                   0005  0004\n");
+    assert_eq!(listing.to_json(), "{\"lines\":[\
+                                   {\"address\":0,\"words\":[31745,32],\"file\":null,\"line\":1,\"column\":1,\"text\":\"SET A, 32\"},\
+                                   {\"address\":2,\"words\":[1,2,3,4],\"file\":\"a.dasm\",\"line\":2,\"column\":5,\"text\":\"DAT \\\"\\\\x\\\", 4\"}]}");
+}
+
+#[test]
+fn test_symbol_map() {
+    let map = SymbolMap::new(vec![
+        Symbol { name: "end".to_string(), value: 0x10, kind: SymbolKind::Label },
+        Symbol { name: "WIDTH".to_string(), value: 0x20, kind: SymbolKind::Constant },
+        Symbol { name: "main".to_string(), value: 0, kind: SymbolKind::Label }
+    ]);
+    assert_eq!(map.to_text(), "0000  label     main\n\
+                               0010  label     end\n\
+                               0020  constant  WIDTH\n");
+    assert_eq!(map.to_json(), "{\"symbols\":[\
+                               {\"name\":\"main\",\"value\":0,\"kind\":\"label\"},\
+                               {\"name\":\"end\",\"value\":16,\"kind\":\"label\"},\
+                               {\"name\":\"WIDTH\",\"value\":32,\"kind\":\"constant\"}]}");
+}
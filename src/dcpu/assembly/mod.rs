@@ -1,2 +1,6 @@
 mod tokenizer;
+mod expression;
+pub mod error;
+pub mod image;
+pub mod listing;
 pub mod parser;
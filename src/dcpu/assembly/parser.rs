@@ -1,25 +1,243 @@
 #![allow(dead_code)]
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use super::tokenizer::Token as Token;
+use super::tokenizer::Value as Value;
+use super::tokenizer::Constant as Constant;
+use super::tokenizer::Directive as Directive;
+use super::tokenizer::Text as Text;
+use super::tokenizer::Operator as Operator;
+use super::tokenizer::Direction as Direction;
 use super::tokenizer::Bits as Bits;
 use super::tokenizer::LookaheadTokenizer as LookaheadTokenizer;
+use super::expression::{Expression, EvalError};
+use super::image::{Image, Segment};
+use super::listing::{Listing, ListingLine, Symbol, SymbolKind, SymbolMap};
+use super::error::{AssembleError, Span};
+use super::super::cpu::memory::{read_words, Endianness};
+use super::super::cpu::debug::{DebugInfo, LineEntry, Label, SourceLocation};
+
+/// encoded value of an instruction together with the next word it needs
+struct Operand {
+    bits: u16,
+    next_word: Option<NextWord>
+}
+
+/// which value of an instruction an operand is
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Slot {
+    A,
+    B
+}
+
+enum NextWord {
+    Word(u16),
+    Expression(Expression, Span)    // refers to labels, known once the whole source is parsed
+}
+
+/// next word which needs the address of a label
+struct Fixup {
+    file: usize,        // where the expression is written
+    segment: usize,
+    offset: usize,      // in the segment
+    expression: Expression,
+    span: Span          // of the whole expression
+}
+
+/// -1..30 fit into the a value itself
+fn is_short_literal(n: u16) -> bool {
+    n <= 30 || n == 0xffff
+}
+
+/// source text kept around to report errors found after it is parsed
+struct SourceFile {
+    path: Option<PathBuf>,
+    text: Rc<str>,
+    line_offset: usize,                 // of a macro body in the file it is defined in
+    call_site: Option<AssembleError>    // of a macro expansion
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: String,
+    file: usize,            // where the macro is defined
+    line_offset: usize,     // lines before the body
+    locals: Vec<String>     // labels defined in the body, unique for each expansion
+}
+
+/// `.if` block being parsed
+struct Condition {
+    span: Span,         // of the directive, to report a missing `.endif`
+    taken: bool,        // lines of the current branch are assembled
+    enclosing: bool,    // the block itself is in an assembled branch
+    else_seen: bool
+}
+
+/// listing line waiting for its words, which are known once fixups are resolved
+struct LineRecord {
+    line: ListingLine,
+    segment: usize,
+    offset: usize,      // of the first word in the segment
+    len: usize
+}
+
+/// how deeply macros may call each other
+const MAX_EXPANSION_DEPTH: usize = 64;
 
 pub struct Parser<'a> {
-    tokenizer: LookaheadTokenizer<'a>
+    tokenizer: LookaheadTokenizer<'a>,
+    file: usize,                    // index of the parsed source in files
+    segments: Vec<Segment>,         // the last one is being assembled
+    org: Option<(usize, Span)>,     // `.org` which started the last segment
+    labels: HashMap<String, u16>,   // label -> address
+    constants: HashMap<String, u16>,
+    fixups: Vec<Fixup>,
+    errors: Vec<AssembleError>,
+    files: Vec<SourceFile>,
+    include_paths: Vec<PathBuf>,
+    include_stack: Vec<PathBuf>,    // files being included, to detect cycles
+    macros: HashMap<String, Macro>,
+    expansions: usize,              // number of macro expansions so far
+    depth: usize,                   // of the macro expansion being parsed
+    locals: Vec<String>,            // labels local to the macro expansion
+    local_suffix: String,           // appended to local labels
+    scope: String,                  // last global label, `.local` labels belong to it
+    numeric_labels: HashMap<u16, usize>, // how many times each numeric label is defined so far
+    conditions: Vec<Condition>,     // open `.if` blocks of the parsed source
+    records: Vec<LineRecord>,
+    listing: Listing                // built from records once the source is assembled
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(source: &str) -> Parser {
+    pub fn new(source: &'a str) -> Parser<'a> {
         Parser {
-            tokenizer: LookaheadTokenizer::new(source)
+            tokenizer: LookaheadTokenizer::new(source),
+            file: 0,
+            segments: vec![Segment::new(0)],
+            org: None,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            fixups: vec![],
+            errors: vec![],
+            files: vec![SourceFile { path: None, text: Rc::from(source), line_offset: 0, call_site: None }],
+            include_paths: vec![],
+            include_stack: vec![],
+            macros: HashMap::new(),
+            expansions: 0,
+            depth: 0,
+            locals: vec![],
+            local_suffix: String::new(),
+            scope: String::new(),
+            numeric_labels: HashMap::new(),
+            conditions: vec![],
+            records: vec![],
+            listing: Listing::default()
         }
     }
 
+    /// path of the source, used in diagnostics and to find files it includes
+    pub fn set_file_name<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref().to_path_buf();
+        self.include_stack = vec![fs::canonicalize(&path).unwrap_or_else(|_| path.clone())];
+        self.files[0].path = Some(path);
+    }
+
+    /// defines a constant before the source is parsed, like `.equ` would,
+    /// to select the configuration assembled with `.if` and `.ifdef`
+    pub fn define(&mut self, name: &str, value: u16) {
+        self.constants.insert(name.to_string(), value);
+    }
+
+    /// directory searched for included files which are not next to the including file
+    pub fn add_include_path<P: AsRef<Path>>(&mut self, path: P) {
+        self.include_paths.push(path.as_ref().to_path_buf());
+    }
+
+    /// moves everything assembled so far to the parser of another file
+    fn swap_state(&mut self, other: &mut Parser) {
+        mem::swap(&mut self.segments, &mut other.segments);
+        mem::swap(&mut self.org, &mut other.org);
+        mem::swap(&mut self.labels, &mut other.labels);
+        mem::swap(&mut self.constants, &mut other.constants);
+        mem::swap(&mut self.fixups, &mut other.fixups);
+        mem::swap(&mut self.errors, &mut other.errors);
+        mem::swap(&mut self.files, &mut other.files);
+        mem::swap(&mut self.include_paths, &mut other.include_paths);
+        mem::swap(&mut self.include_stack, &mut other.include_stack);
+        mem::swap(&mut self.macros, &mut other.macros);
+        mem::swap(&mut self.expansions, &mut other.expansions);
+        mem::swap(&mut self.numeric_labels, &mut other.numeric_labels);
+        mem::swap(&mut self.records, &mut other.records);
+    }
+
+    /// source lines with the words assembled from them, filled by `parse`
+    pub fn listing(&self) -> &Listing {
+        &self.listing
+    }
+
+    /// labels and constants, numeric labels are left out as they have no name
+    pub fn symbol_map(&self) -> SymbolMap {
+        let labels = self.labels.iter()
+            .filter(|&(name, _)| !name.contains(':'))
+            .map(|(name, &value)| Symbol { name: name.clone(), value, kind: SymbolKind::Label });
+        let constants = self.constants.iter()
+            .map(|(name, &value)| Symbol { name: name.clone(), value, kind: SymbolKind::Constant });
+        SymbolMap::new(labels.chain(constants).collect())
+    }
+
+    /// addresses of the lines which assembled to any words, with the labels
+    pub fn debug_info(&self) -> DebugInfo {
+        let lines = self.listing.lines.iter()
+            .filter(|line| !line.words.is_empty())
+            .map(|line| LineEntry {
+                address: line.address,
+                len: line.words.len() as u16,
+                location: SourceLocation { file: line.file.clone(), line: line.line, column: line.column }
+            });
+        let labels = self.symbol_map().symbols.into_iter()
+            .filter(|symbol| symbol.kind == SymbolKind::Label)
+            .map(|symbol| Label { name: symbol.name, address: symbol.value });
+        DebugInfo { lines: lines.collect(), labels: labels.collect() }
+    }
+
+    /// address of the next assembled word
+    fn address(&self) -> u16 {
+        self.segments.last().unwrap().end()
+    }
+
+    /// false once the last segment wraps around the end of memory
+    fn fits_in_memory(&self) -> bool {
+        let segment = self.segments.last().unwrap();
+        segment.origin as usize + segment.words.len() <= 0x10000
+    }
+
+    fn push_word(&mut self, word: u16) {
+        self.segments.last_mut().unwrap().words.push(word);
+    }
+
     fn skip_whitesigns(&mut self) {
         while matches!(self.tokenizer.token_at(0), Some(Token::Whitespace) | Some(Token::Endline)) {
             self.tokenizer.advance(1);
         }
     }
 
+    fn skip_whitespace(&mut self) {
+        while self.is_whitespace(0) {
+            self.tokenizer.advance(1);
+        }
+    }
+
+    /// skips everything up to the end of the line, used to recover after an error
+    fn skip_line(&mut self) {
+        while self.tokenizer.token_at(0).is_some() && !self.is_endline(0) {
+            self.tokenizer.advance(1);
+        }
+    }
+
     fn is_opcode(&mut self, n: usize) -> bool {
         matches!(self.tokenizer.token_at(n), Some(Token::Opcode(ref _op)))
     }
@@ -28,78 +246,1619 @@ impl<'a> Parser<'a> {
         matches!(self.tokenizer.token_at(n), Some(Token::Whitespace))
     }
 
+    fn is_endline(&mut self, n: usize) -> bool {
+        matches!(self.tokenizer.token_at(n), Some(Token::Endline))
+    }
+
     fn is_value(&mut self, n: usize) -> bool {
         matches!(self.tokenizer.token_at(n), Some(Token::Value(ref _a)))
     }
+
+    fn is_name(&mut self, n: usize) -> bool {
+        matches!(self.tokenizer.token_at(n), Some(Token::Value(Value::Name(_))))
+    }
     
     fn is_comma(&mut self, n: usize) -> bool {
         matches!(self.tokenizer.token_at(n), Some(Token::Comma))
     }
 
-    fn bits_at(&mut self, n: usize) -> u16 {
-        self.tokenizer.token_at(n).unwrap().to_bits() as u16
+    fn is_colon(&mut self, n: usize) -> bool {
+        matches!(self.tokenizer.token_at(n), Some(Token::Colon))
+    }
+
+    fn is_open_bracket(&mut self, n: usize) -> bool {
+        matches!(self.tokenizer.token_at(n), Some(Token::OpenBracket))
+    }
+
+    fn is_close_bracket(&mut self, n: usize) -> bool {
+        matches!(self.tokenizer.token_at(n), Some(Token::CloseBracket))
+    }
+
+    fn is_plus(&mut self, n: usize) -> bool {
+        matches!(self.tokenizer.token_at(n), Some(Token::Operator(Operator::Add)))
+    }
+
+    fn is_minus(&mut self, n: usize) -> bool {
+        matches!(self.tokenizer.token_at(n), Some(Token::Operator(Operator::Sub)))
+    }
+
+    /// true if an expression can start with the nth token
+    fn is_expression(&mut self, n: usize) -> bool {
+        matches!(self.tokenizer.token_at(n),
+                 Some(Token::Value(Value::Number(_))) | Some(Token::Value(Value::Name(_))) | Some(Token::Value(Value::NumericLabel(..))) |
+                 Some(Token::OpenParen) | Some(Token::Operator(Operator::Sub)) | Some(Token::Operator(Operator::Not)))
+    }
+
+    /// true if the first token after the nth one, ignoring whitespace, is a register
+    fn is_register_after(&mut self, mut n: usize) -> bool {
+        n += 1;
+        while self.is_whitespace(n) {
+            n += 1;
+        }
+        matches!(self.tokenizer.token_at(n), Some(Token::Value(Value::Constant(_))))
+    }
+
+    fn name_at(&mut self, n: usize) -> &'a str {
+        match self.tokenizer.token_at(n) {
+            Some(Token::Value(Value::Name(name))) => name,
+            _ => unreachable!()
+        }
+    }
+
+    fn error_at_span(&self, span: Span, message: String) -> AssembleError {
+        self.error_in(self.file, span, message)
+    }
+
+    fn error_in(&self, file: usize, span: Span, message: String) -> AssembleError {
+        let file = &self.files[file];
+        let mut err = AssembleError::new(&file.text, span, message)
+            .with_file(file.path.as_ref().map(|path| path.display().to_string()));
+        err.line += file.line_offset;
+        err.called_from = file.call_site.clone().map(Box::new);
+        err
+    }
+
+    /// name of the symbol in the symbol table, labels local to a macro expansion
+    /// get a unique suffix and `.local` labels the name of the global label before them
+    fn symbol_name(&self, name: &str) -> String {
+        if self.locals.iter().any(|local| local == name) {
+            format!("{}{}", name, self.local_suffix)
+        } else if name.starts_with('.') {
+            format!("{}{}", self.scope, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// numeric labels are named after the number and how many times it has been defined,
+    /// so that `1b` is the last definition of `1:` and `1f` the next one
+    fn numeric_label_name(&self, n: u16, direction: Direction) -> String {
+        let count = self.numeric_labels.get(&n).cloned().unwrap_or(0);
+        match direction {
+            Direction::Backward => format!("{}:{}", n, count),
+            Direction::Forward => format!("{}:{}", n, count + 1)
+        }
+    }
+
+    /// error pointing at the nth token
+    fn error_at<T>(&mut self, n: usize, message: &str) -> Result<T, AssembleError> {
+        let span = self.tokenizer.span_at(n);
+        let message = match self.tokenizer.token_at(n) {
+            Some(Token::Invalid(text, _)) if is_reserved_name(text) => reserved_name_message(text),
+            Some(Token::Invalid(text, _)) => format!("{}, found invalid token `{}`", message, text),
+            Some(Token::Overflow(text, _)) => format!("number `{}` does not fit into 16 bits", text),
+            _ => message.to_string()
+        };
+        Err(self.error_at_span(span, message))
+    }
+
+    fn expect_end_of_line(&mut self) -> Result<(), AssembleError> {
+        self.skip_whitespace();
+        match self.tokenizer.token_at(0) {
+            None | Some(Token::Endline) => Ok(()),
+            _ => self.error_at(0, "expected end of line")
+        }
+    }
+
+    /// parses `:label`, `label:` or `1:` and binds the label to the current address
+    fn parse_label(&mut self) -> Result<bool, AssembleError> {
+        if let (Some(Token::Value(Value::Number(n))), true) = (self.tokenizer.token_at(0), self.is_colon(1)) {
+            self.tokenizer.advance(2);
+            *self.numeric_labels.entry(n).or_insert(0) += 1;
+            let name = self.numeric_label_name(n, Direction::Backward);
+            let address = self.address();
+            self.labels.insert(name, address);
+            return Ok(true);
+        }
+        let (name, span) = if self.is_colon(0) && self.is_name(1) {
+            (self.name_at(1), self.tokenizer.span_at(1))
+        } else if self.is_name(0) && self.is_colon(1) {
+            (self.name_at(0), self.tokenizer.span_at(0))
+        } else {
+            return Ok(false);
+        };
+        self.tokenizer.advance(2);
+        if !name.starts_with('.') {
+            self.scope = name.to_string();
+        }
+        let name = self.symbol_name(name);
+        self.check_not_defined(&name, span)?;
+        let address = self.address();
+        self.labels.insert(name, address);
+        Ok(true)
+    }
+
+    /// labels and constants share one namespace
+    fn check_not_defined(&self, name: &str, span: Span) -> Result<(), AssembleError> {
+        if self.labels.contains_key(name) {
+            return Err(self.error_at_span(span, format!("label `{}` is already defined", name)));
+        }
+        if self.constants.contains_key(name) {
+            return Err(self.error_at_span(span, format!("constant `{}` is already defined", name)));
+        }
+        Ok(())
+    }
+
+    /// parses `[register]`, `[register + offset]`, `[register - offset]`,
+    /// `[offset + register]` or `[address]`, where offset and address are expressions
+    fn parse_indirect(&mut self) -> Result<Operand, AssembleError> {
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        let mut register = None;
+        let mut offset = None;
+        loop {
+            match self.tokenizer.token_at(0) {
+                Some(Token::Value(Value::Constant(c))) if register.is_none() && (c.is_general() || c == Constant::SP) => {
+                    register = Some(c);
+                    self.tokenizer.advance(1);
+                },
+                Some(Token::Value(Value::Constant(_))) if register.is_none() => return self.error_at(0, "expected A, B, C, X, Y, Z, I, J or SP"),
+                Some(Token::Value(Value::Constant(_))) => return self.error_at(0, "expected number or label, register is already given"),
+                _ if self.is_expression(0) && offset.is_none() => offset = Some(self.parse_next_word()?),
+                _ if self.is_expression(0) => return self.error_at(0, "expected register, offset is already given"),
+                _ => return self.error_at(0, "expected register, number or label")
+            }
+            self.skip_whitespace();
+            if self.is_plus(0) {
+                self.tokenizer.advance(1);
+                self.skip_whitespace();
+            } else if !(self.is_minus(0) && register.is_some() && offset.is_none()) {
+                break;  // `[register - offset]` continues with a negated offset
+            }
+        }
+        if !self.is_close_bracket(0) {
+            return self.error_at(0, "expected `]`");
+        }
+        self.tokenizer.advance(1);
+
+        let (bits, next_word) = match (register, offset) {
+            (Some(Constant::SP), None) => (0x19, None),                    // [SP] / PEEK
+            (Some(Constant::SP), offset) => (0x1a, offset),                // [SP + next word] / PICK n
+            (Some(r), None) => (0x08 + r.to_bits() as u16, None),
+            (Some(r), offset) => (0x10 + r.to_bits() as u16, offset),
+            (None, offset) => (0x1e, offset)
+        };
+        Ok(Operand { bits, next_word })
+    }
+
+    /// binding power of binary operators, higher binds tighter
+    fn precedence(op: Operator) -> Option<u8> {
+        match op {
+            Operator::Or => Some(1),
+            Operator::Xor => Some(2),
+            Operator::And => Some(3),
+            Operator::Shl | Operator::Shr => Some(4),
+            Operator::Add | Operator::Sub => Some(5),
+            Operator::Mul | Operator::Div | Operator::Mod => Some(6),
+            Operator::Not => None
+        }
+    }
+
+    /// parses a number, label, parenthesized expression or unary operator
+    fn parse_primary(&mut self) -> Result<Expression, AssembleError> {
+        let expression = match self.tokenizer.token_at(0) {
+            Some(Token::Value(Value::Number(n))) => Expression::Number(n),
+            Some(Token::Value(Value::Name(name))) => Expression::Symbol(self.symbol_name(name), self.tokenizer.span_at(0)),
+            Some(Token::Value(Value::NumericLabel(n, direction))) => {
+                Expression::Symbol(self.numeric_label_name(n, direction), self.tokenizer.span_at(0))
+            },
+            Some(Token::Operator(op)) if op == Operator::Sub || op == Operator::Not => {
+                self.tokenizer.advance(1);
+                self.skip_whitespace();
+                return Ok(Expression::Unary(op, Box::new(self.parse_primary()?)));
+            },
+            Some(Token::OpenParen) => {
+                self.tokenizer.advance(1);
+                self.skip_whitespace();
+                let expression = self.parse_binary(0)?;
+                self.skip_whitespace();
+                if !matches!(self.tokenizer.token_at(0), Some(Token::CloseParen)) {
+                    return self.error_at(0, "expected `)`");
+                }
+                expression
+            },
+            _ => return self.error_at(0, "expected number or label")
+        };
+        self.tokenizer.advance(1);
+        Ok(expression)
+    }
+
+    /// parses operators binding at least as tight as min_precedence,
+    /// stops before `+ register` so that `[offset + register]` works
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expression, AssembleError> {
+        let mut expression = self.parse_primary()?;
+        loop {
+            let mut n = 0;
+            while self.is_whitespace(n) {
+                n += 1;
+            }
+            let op = match self.tokenizer.token_at(n) {
+                Some(Token::Operator(op)) => op,
+                _ => break
+            };
+            let precedence = match Parser::precedence(op) {
+                Some(precedence) if precedence >= min_precedence => precedence,
+                _ => break
+            };
+            if op == Operator::Add && self.is_register_after(n) {
+                break;
+            }
+            self.tokenizer.advance(n + 1);
+            self.skip_whitespace();
+            let right = self.parse_binary(precedence + 1)?;
+            expression = Expression::Binary(op, Box::new(expression), Box::new(right));
+        }
+        Ok(expression)
+    }
+
+    /// parses an expression, evaluating it right away if it does not refer to any label
+    fn parse_next_word(&mut self) -> Result<NextWord, AssembleError> {
+        let start = self.tokenizer.span_at(0).start;
+        let expression = self.parse_binary(0)?;
+        let span = self.tokenizer.span_from(start);
+        let constants = &self.constants;
+        match expression.evaluate(&|name| constants.get(name).cloned()) {
+            Ok(value) => Ok(NextWord::Word(value)),
+            Err(EvalError::Undefined(..)) => Ok(NextWord::Expression(expression, span)),
+            Err(err) => Err(self.eval_error(self.file, err, span))
+        }
     }
 
-    fn parse_expression(&mut self) -> u16 {
-        if  self.is_opcode(0) &&
-            self.is_whitespace(1) &&
-            self.is_value(2) &&
-            self.is_comma(3) &&
-            self.is_whitespace(4) &&
-            self.is_value(5) {
-            let op = self.bits_at(0);
-            let b = self.bits_at(2);
-            let a = self.bits_at(5);
-            self.tokenizer.advance(6);
-            return op + (b << 5) + (a << 10);
+    /// parses an expression which has to be known right away,
+    /// so it can only refer to constants and labels defined above
+    fn parse_immediate(&mut self) -> Result<u16, AssembleError> {
+        let start = self.tokenizer.span_at(0).start;
+        let expression = self.parse_binary(0)?;
+        let span = self.tokenizer.span_from(start);
+        match expression.evaluate(&|name| self.lookup(name)) {
+            Ok(value) => Ok(value),
+            Err(err) => Err(self.eval_error(self.file, err, span))
         }
+    }
 
-        if  self.is_opcode(0) &&
-            self.is_whitespace(1) &&
-            self.is_value(2) {
-            let op = self.bits_at(0);
-            let a = self.bits_at(2);
-            self.tokenizer.advance(3);
-            return (op << 5) + (a << 10);
+    fn lookup(&self, name: &str) -> Option<u16> {
+        self.labels.get(name).or_else(|| self.constants.get(name)).cloned()
+    }
+
+    fn eval_error(&self, file: usize, err: EvalError, span: Span) -> AssembleError {
+        match err {
+            // named as written, symbol names of local labels are made up
+            EvalError::Undefined(_, span) => {
+                let name = &self.files[file].text[span.start..span.end];
+                self.error_in(file, span, format!("label `{}` is not defined", name))
+            },
+            EvalError::DivisionByZero => self.error_in(file, span, "division by zero".to_string()),
+            EvalError::Overflow(Some(value)) => self.error_in(file, span, format!("value `{}` does not fit into 16 bits", value)),
+            EvalError::Overflow(None) => self.error_in(file, span, "value does not fit into 16 bits".to_string())
         }
-        panic!();
     }
 
-    pub fn parse(&mut self) -> Vec<u16> {
-        let mut result = vec!();
+    fn parse_operand(&mut self, slot: Slot) -> Result<Operand, AssembleError> {
+        if self.is_open_bracket(0) {
+            return self.parse_indirect();
+        }
+        if self.is_expression(0) {
+            return match self.parse_next_word()? {
+                NextWord::Word(n) if slot == Slot::A && is_short_literal(n) => Ok(Operand {
+                    bits: n.wrapping_add(0x21),
+                    next_word: None
+                }),
+                next_word => Ok(Operand { bits: 0x1f, next_word: Some(next_word) })
+            };
+        }
+        let operand = match self.tokenizer.token_at(0) {
+            Some(Token::Value(Value::Constant(Constant::PUSH))) if slot == Slot::A => return self.error_at(0, "PUSH can only be used as b"),
+            Some(Token::Value(Value::Constant(Constant::POP))) if slot == Slot::B => return self.error_at(0, "POP can only be used as a"),
+            Some(Token::Value(Value::Constant(Constant::PICK))) => {
+                self.tokenizer.advance(1);
+                self.skip_whitespace();
+                return Ok(Operand {
+                    bits: 0x1a,
+                    next_word: Some(self.parse_next_word()?)
+                });
+            },
+            Some(Token::Value(Value::Constant(c))) => Operand {
+                bits: c.to_bits() as u16,
+                next_word: None
+            },
+            _ => return self.error_at(0, "expected value")
+        };
+        self.tokenizer.advance(1);
+        Ok(operand)
+    }
+
+    fn parse_instruction(&mut self) -> Result<(), AssembleError> {
+        let op = match self.tokenizer.token_at(0) {
+            Some(Token::Opcode(op)) => op,
+            Some(Token::Value(Value::Name(name))) => {
+                let message = format!("unknown instruction `{}`", name);
+                return self.error_at(0, &message);
+            },
+            _ => return self.error_at(0, "expected instruction")
+        };
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        if op.is_special() {
+            let a = self.parse_operand(Slot::A)?;
+            self.emit(((op.to_bits() as u16) << 5) + (a.bits << 10), vec![a]);
+            return Ok(());
+        }
+
+        let b = self.parse_operand(Slot::B)?;
+        self.skip_whitespace();
+        if !self.is_comma(0) {
+            return self.error_at(0, "expected `,`");
+        }
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        let a = self.parse_operand(Slot::A)?;
+        // a is handled before b, so its next word comes first
+        self.emit(op.to_bits() as u16 + (b.bits << 5) + (a.bits << 10), vec![a, b]);
+        Ok(())
+    }
+
+    /// words of a string, one character each or two when packed
+    fn text_words(&mut self, text: Text) -> Result<Vec<u16>, AssembleError> {
+        let limit = if text.packed { 0xff } else { 0xffff };
+        let chars = text.chars();
+        if let Some(&c) = chars.iter().find(|&&c| c as u32 > limit) {
+            let message = format!("character `{}` does not fit into a {} string", c,
+                                  if text.packed { "packed" } else { "16 bit" });
+            return self.error_at(0, &message);
+        }
+        let mut words: Vec<u16> = match text.packed {
+            true => chars.chunks(2)
+                .map(|pair| ((pair[0] as u16) << 8) | pair.get(1).map_or(0, |&c| c as u16))
+                .collect(),
+            false => chars.iter().map(|&c| c as u16).collect()
+        };
+        if text.zero_terminated {
+            words.push(0);
+        }
+        Ok(words)
+    }
+
+    /// parses a comma separated list of expressions and strings following DAT
+    fn parse_data(&mut self) -> Result<(), AssembleError> {
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        loop {
+            match self.tokenizer.token_at(0) {
+                Some(Token::String(text)) => {
+                    for word in self.text_words(text)? {
+                        self.push_word(word);
+                    }
+                    self.tokenizer.advance(1);
+                },
+                _ if self.is_expression(0) => {
+                    let next_word = self.parse_next_word()?;
+                    self.push_next_word(next_word);
+                },
+                _ => return self.error_at(0, "expected number, label or string")
+            }
+            self.skip_whitespace();
+            if !self.is_comma(0) {
+                return Ok(());
+            }
+            self.tokenizer.advance(1);
+            self.skip_whitespace();
+        }
+    }
+
+    /// parses `.org address`
+    fn parse_org(&mut self) -> Result<(), AssembleError> {
+        let span = self.tokenizer.span_at(0);
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        let origin = self.parse_immediate()?;
+        if self.segments.last().unwrap().words.is_empty() {
+            self.segments.pop();
+        } else {
+            self.check_overlap();
+        }
+        self.segments.push(Segment::new(origin));
+        self.org = Some((self.file, span));
+        Ok(())
+    }
+
+    /// reports the last segment if it overwrites words of an earlier one
+    fn check_overlap(&mut self) {
+        let (last, earlier) = self.segments.split_last().unwrap();
+        let address = earlier.iter().filter_map(|segment| segment.overlap(last)).next();
+        if let (Some(address), Some((file, span))) = (address, self.org) {
+            let err = self.error_in(file, span, format!("`.org` overwrites words already assembled at {:#06x}", address));
+            self.errors.push(err);
+        }
+    }
+
+    /// parses `.reserve count`, `.fill count, word` or `.align multiple`
+    fn parse_fill(&mut self, directive: Directive) -> Result<(), AssembleError> {
+        if !matches!(directive, Directive::Reserve | Directive::Fill | Directive::Align) {
+            return self.error_at(0, "conditional directives have to be on a line of their own");
+        }
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        let span = self.tokenizer.span_at(0);
+        let n = self.parse_immediate()?;
+        let (count, word) = match directive {
+            Directive::Reserve => (n, 0),
+            Directive::Align if n == 0 => return Err(self.error_at_span(span, "alignment has to be greater than zero".to_string())),
+            Directive::Align => ((n - self.address() % n) % n, 0),
+            Directive::Fill => {
+                self.skip_whitespace();
+                if !self.is_comma(0) {
+                    return self.error_at(0, "expected `,`");
+                }
+                self.tokenizer.advance(1);
+                self.skip_whitespace();
+                (n, self.parse_immediate()?)
+            },
+            _ => unreachable!()
+        };
+        for _i in 0..count {
+            self.push_word(word);
+        }
+        Ok(())
+    }
+
+    /// parses `.equ name, value`, `.equ name value` or `#define name value`
+    fn parse_equ(&mut self) -> Result<(), AssembleError> {
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        if !self.is_name(0) {
+            return self.error_at(0, "expected name");
+        }
+        let (name, span) = (self.name_at(0), self.tokenizer.span_at(0));
+        self.check_not_defined(name, span)?;
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        if self.is_comma(0) {
+            self.tokenizer.advance(1);
+            self.skip_whitespace();
+        }
+        let value = self.parse_immediate()?;
+        self.constants.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// parses the quoted file name of .include or .incbin and looks for the file
+    fn parse_file_name(&mut self) -> Result<(String, PathBuf), AssembleError> {
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        let name: String = match self.tokenizer.token_at(0) {
+            Some(Token::String(text)) => text.chars().into_iter().collect(),
+            _ => return self.error_at(0, "expected file name")
+        };
+        let span = self.tokenizer.span_at(0);
+        self.tokenizer.advance(1);
+        match self.find_file(&name) {
+            Some(path) => Ok((name, path)),
+            None => Err(self.error_at_span(span, format!("cannot find `{}`", name)))
+        }
+    }
+
+    /// looks for a file next to the parsed one, then in the include paths
+    fn find_file(&self, name: &str) -> Option<PathBuf> {
+        let directory = self.files[self.file].path.as_ref()
+            .and_then(|path| path.parent())
+            .map_or(PathBuf::new(), Path::to_path_buf);
+        iter::once(directory)
+            .chain(self.include_paths.iter().cloned())
+            .map(|directory| directory.join(name))
+            .find(|path| path.is_file())
+    }
+
+    /// parses `.include "file"` and assembles the file in its place
+    fn parse_include(&mut self) -> Result<(), AssembleError> {
+        let span = self.tokenizer.span_at(0);
+        let (name, path) = self.parse_file_name()?;
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if self.include_stack.contains(&canonical) {
+            return Err(self.error_at_span(span, format!("`{}` includes itself", name)));
+        }
+        let text: Rc<str> = match fs::read_to_string(&path) {
+            Ok(text) => Rc::from(text),
+            Err(err) => return Err(self.error_at_span(span, format!("cannot read `{}`: {}", name, err)))
+        };
+
+        self.files.push(SourceFile { path: Some(path), text: text.clone(), line_offset: 0, call_site: None });
+        self.include_stack.push(canonical);
+        let mut parser = Parser::new(&text);
+        self.swap_state(&mut parser);
+        parser.file = parser.files.len() - 1;
+        parser.parse_lines();
+        self.swap_state(&mut parser);
+        self.include_stack.pop();
+        Ok(())
+    }
+
+    /// parses `.incbin "file"` optionally followed by `, little` or `, big`,
+    /// words are big endian by default
+    fn parse_incbin(&mut self) -> Result<(), AssembleError> {
+        let span = self.tokenizer.span_at(0);
+        let (name, path) = self.parse_file_name()?;
+        self.skip_whitespace();
+        let mut endianness = Endianness::Big;
+        if self.is_comma(0) {
+            self.tokenizer.advance(1);
+            self.skip_whitespace();
+            endianness = match self.tokenizer.token_at(0) {
+                Some(Token::Value(Value::Name("little"))) => Endianness::Little,
+                Some(Token::Value(Value::Name("big"))) => Endianness::Big,
+                _ => return self.error_at(0, "expected `little` or `big`")
+            };
+            self.tokenizer.advance(1);
+        }
+        match File::open(&path).and_then(|file| read_words(file, endianness)) {
+            Ok(words) => words.into_iter().for_each(|word| self.push_word(word)),
+            Err(err) => return Err(self.error_at_span(span, format!("cannot read `{}`: {}", name, err)))
+        }
+        Ok(())
+    }
+
+    /// parses `.macro name(param, ...)` and the body up to `.endmacro`
+    fn parse_macro(&mut self) -> Result<(), AssembleError> {
+        let macro_span = self.tokenizer.span_at(0);
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        if !self.is_name(0) {
+            return self.error_at(0, "expected macro name");
+        }
+        let (name, name_span) = (self.name_at(0), self.tokenizer.span_at(0));
+        self.tokenizer.advance(1);
+        let mut params = vec![];
+        if matches!(self.tokenizer.token_at(0), Some(Token::OpenParen)) {
+            self.tokenizer.advance(1);
+            self.skip_whitespace();
+            while !matches!(self.tokenizer.token_at(0), Some(Token::CloseParen)) {
+                if !params.is_empty() {
+                    if !self.is_comma(0) {
+                        return self.error_at(0, "expected `,` or `)`");
+                    }
+                    self.tokenizer.advance(1);
+                    self.skip_whitespace();
+                }
+                if !self.is_name(0) {
+                    return self.error_at(0, "expected parameter name");
+                }
+                params.push(self.name_at(0).to_string());
+                self.tokenizer.advance(1);
+                self.skip_whitespace();
+            }
+            self.tokenizer.advance(1);
+        }
+        self.expect_end_of_line()?;
+
+        let start = self.tokenizer.span_at(0).end;    // after the end of line
+        let mut locals = vec![];
+        loop {
+            match self.tokenizer.token_at(0) {
+                None => return Err(self.error_at_span(macro_span, "missing `.endmacro`".to_string())),
+                Some(Token::Directive(Directive::Endmacro)) => break,
+                Some(Token::Directive(Directive::Macro)) => return self.error_at(0, "macros cannot be defined inside macros"),
+                Some(Token::Invalid(text, _)) if is_reserved_name(text) => {
+                    // the body is only parsed when the macro is expanded
+                    let span = self.tokenizer.span_at(0);
+                    let err = self.error_at_span(span, reserved_name_message(text));
+                    self.errors.push(err);
+                },
+                Some(Token::Value(Value::Name(label))) if self.is_colon(1) => locals.push(label.to_string()),
+                Some(Token::Colon) if self.is_name(1) => locals.push(self.name_at(1).to_string()),
+                _ => {}
+            }
+            self.tokenizer.advance(1);
+        }
+        let end = self.tokenizer.span_at(0).start.max(start);
+        self.tokenizer.advance(1);
+
+        if self.macros.contains_key(name) {
+            return Err(self.error_at_span(name_span, format!("macro `{}` is already defined", name)));
+        }
+        let source = self.tokenizer.source();
+        let body = Macro {
+            params,
+            body: source[start.min(end)..end].to_string(),
+            file: self.file,
+            line_offset: self.files[self.file].line_offset + source[..start.min(end)].matches('\n').count(),
+            locals
+        };
+        self.macros.insert(name.to_string(), body);
+        Ok(())
+    }
+
+    /// parses the arguments of a macro call, `name(argument, ...)` or just `name`,
+    /// and assembles the macro body with the arguments in place of the parameters
+    fn parse_macro_call(&mut self) -> Result<(), AssembleError> {
+        let name = self.name_at(0);
+        let start = self.tokenizer.span_at(0).start;
+        self.tokenizer.advance(1);
+        let source = self.tokenizer.source();
+        let mut args = vec![];
+        if matches!(self.tokenizer.token_at(0), Some(Token::OpenParen)) {
+            self.tokenizer.advance(1);
+            let mut arg_start = self.tokenizer.span_at(0).start;
+            let mut depth = 0;
+            loop {
+                match self.tokenizer.token_at(0) {
+                    None | Some(Token::Endline) => return self.error_at(0, "expected `)`"),
+                    Some(Token::OpenParen) | Some(Token::OpenBracket) => depth += 1,
+                    Some(Token::CloseParen) | Some(Token::CloseBracket) if depth > 0 => depth -= 1,
+                    Some(Token::Invalid(text, _)) if is_reserved_name(text) => return self.error_at(0, "expected argument"),
+                    Some(Token::Comma) | Some(Token::CloseParen) if depth == 0 => {
+                        let arg = source[arg_start..self.tokenizer.span_at(0).start].trim();
+                        let done = !self.is_comma(0);
+                        if !(done && arg.is_empty() && args.is_empty()) {
+                            // local labels of the calling macro have to keep referring to it
+                            let mangled: Vec<String> = self.locals.iter().map(|local| self.symbol_name(local)).collect();
+                            args.push(substitute(arg, &self.locals, &mangled));
+                        }
+                        self.tokenizer.advance(1);
+                        if done {
+                            break;
+                        }
+                        arg_start = self.tokenizer.span_at(0).start;
+                        continue;
+                    },
+                    _ => {}
+                }
+                self.tokenizer.advance(1);
+            }
+        }
+        let span = self.tokenizer.span_from(start);
+
+        let expanded = {
+            let body = &self.macros[name];
+            if args.len() != body.params.len() {
+                let message = format!("macro `{}` takes {} arguments, found {}", name, body.params.len(), args.len());
+                return Err(self.error_at_span(span, message));
+            }
+            substitute(&body.body, &body.params, &args)
+        };
+        if self.depth >= MAX_EXPANSION_DEPTH {
+            return Err(self.error_at_span(span, format!("macro `{}` is expanded too deeply", name)));
+        }
+
+        let call_site = self.error_at_span(span, format!("in expansion of macro `{}`", name));
+        let text: Rc<str> = Rc::from(expanded);
+        let (path, line_offset, locals) = {
+            let body = &self.macros[name];
+            (self.files[body.file].path.clone(), body.line_offset, body.locals.clone())
+        };
+        self.files.push(SourceFile { path, text: text.clone(), line_offset, call_site: Some(call_site) });
+        self.expansions += 1;
+
+        let mut parser = Parser::new(&text);
+        parser.tokenizer = LookaheadTokenizer::with_mangled_names(&text);
+        self.swap_state(&mut parser);
+        parser.file = parser.files.len() - 1;
+        parser.depth = self.depth + 1;
+        parser.locals = locals;
+        parser.local_suffix = format!("@{}", parser.expansions);
+        parser.scope = self.scope.clone();
+        parser.parse_lines();
+        self.swap_state(&mut parser);
+        Ok(())
+    }
+
+    fn push_next_word(&mut self, next_word: NextWord) {
+        match next_word {
+            NextWord::Word(word) => self.push_word(word),
+            NextWord::Expression(expression, span) => {
+                let segment = self.segments.len() - 1;
+                let offset = self.segments[segment].words.len();
+                self.fixups.push(Fixup { file: self.file, segment, offset, expression, span });
+                self.push_word(0);
+            }
+        }
+    }
+
+    fn emit(&mut self, word: u16, operands: Vec<Operand>) {
+        self.push_word(word);
+        for next_word in operands.into_iter().filter_map(|operand| operand.next_word) {
+            self.push_next_word(next_word);
+        }
+    }
+
+    /// evaluates expressions referring to labels and writes them into their next words
+    fn resolve_fixups(&mut self) {
+        for fixup in mem::take(&mut self.fixups) {
+            match fixup.expression.evaluate(&|name| self.lookup(name)) {
+                Ok(value) => self.segments[fixup.segment].words[fixup.offset] = value,
+                Err(err) => {
+                    let err = self.eval_error(fixup.file, err, fixup.span);
+                    self.errors.push(err);
+                }
+            }
+        }
+    }
+
+    /// false inside a branch of an `.if` block which is not assembled
+    #[allow(clippy::unnecessary_map_or)]    // is_none_or needs rust 1.82
+    fn is_assembling(&self) -> bool {
+        self.conditions.last().map_or(true, |condition| condition.taken)
+    }
+
+    /// parses the condition of `.if value`, `.ifdef name` or `.ifndef name`
+    fn parse_if(&mut self, directive: Directive) -> Result<bool, AssembleError> {
+        self.tokenizer.advance(1);
+        self.skip_whitespace();
+        if directive == Directive::If {
+            return Ok(self.parse_immediate()? != 0);
+        }
+        if !self.is_name(0) {
+            return self.error_at(0, "expected name");
+        }
+        let name = self.name_at(0);
+        let name = self.symbol_name(name);
+        self.tokenizer.advance(1);
+        Ok(self.lookup(&name).is_some() == (directive == Directive::Ifdef))
+    }
+
+    /// parses `.if value`, `.ifdef name`, `.ifndef name`, `.else` or `.endif`,
+    /// returns false if the line does not start with any of them
+    fn parse_condition(&mut self) -> Result<bool, AssembleError> {
+        let directive = match self.tokenizer.token_at(0) {
+            Some(Token::Directive(directive)) => directive,
+            _ => return Ok(false)
+        };
+        let span = self.tokenizer.span_at(0);
+        match directive {
+            Directive::If | Directive::Ifdef | Directive::Ifndef if !self.is_assembling() => {
+                self.skip_line();
+                self.conditions.push(Condition { span, taken: false, enclosing: false, else_seen: false });
+            },
+            Directive::If | Directive::Ifdef | Directive::Ifndef => {
+                let taken = self.parse_if(directive);
+                // a broken condition still opens a block, neither branch of which is assembled
+                let condition = match taken {
+                    Ok(taken) => Condition { span, taken, enclosing: true, else_seen: false },
+                    Err(_) => Condition { span, taken: false, enclosing: false, else_seen: false }
+                };
+                self.conditions.push(condition);
+                taken?;
+            },
+            Directive::Else => {
+                self.tokenizer.advance(1);
+                let message = match self.conditions.last_mut() {
+                    None => "`.else` without `.if`",
+                    Some(ref condition) if condition.else_seen => "`.else` after `.else`",
+                    Some(condition) => {
+                        condition.taken = condition.enclosing && !condition.taken;
+                        condition.else_seen = true;
+                        return Ok(true);
+                    }
+                };
+                return Err(self.error_at_span(span, message.to_string()));
+            },
+            Directive::Endif => {
+                self.tokenizer.advance(1);
+                if self.conditions.pop().is_none() {
+                    return Err(self.error_at_span(span, "`.endif` without `.if`".to_string()));
+                }
+            },
+            _ => return Ok(false)
+        }
+        Ok(true)
+    }
+
+    /// parses labels and an instruction up to the end of the line
+    fn parse_line(&mut self) -> Result<(), AssembleError> {
+        if self.parse_condition()? {
+            return self.expect_end_of_line();
+        }
+        if !self.is_assembling() {
+            self.skip_line();
+            return Ok(());
+        }
+        while self.parse_label()? {
+            self.skip_whitespace();
+        }
+        // a line with only labels, the next line may start a condition
+        let record = match self.tokenizer.token_at(0) {
+            None | Some(Token::Endline) => return Ok(()),
+            Some(_) => self.start_record()
+        };
+        // lines of included files and macro expansions are listed on their own
+        let nested = match self.tokenizer.token_at(0) {
+            Some(Token::Directive(Directive::Include)) => true,
+            Some(Token::Value(Value::Name(name))) => self.macros.contains_key(name),
+            _ => false
+        };
+        let start = self.tokenizer.span_at(0).start;
+        let fitted = self.fits_in_memory();
+        match self.tokenizer.token_at(0) {
+            Some(Token::Directive(Directive::Dat)) => self.parse_data()?,
+            Some(Token::Directive(Directive::Org)) => self.parse_org()?,
+            Some(Token::Directive(Directive::Equ)) => self.parse_equ()?,
+            Some(Token::Directive(Directive::Include)) => self.parse_include()?,
+            Some(Token::Directive(Directive::Incbin)) => self.parse_incbin()?,
+            Some(Token::Directive(Directive::Macro)) => self.parse_macro()?,
+            Some(Token::Directive(Directive::Endmacro)) => return self.error_at(0, "`.endmacro` without `.macro`"),
+            Some(Token::Value(Value::Name(name))) if self.macros.contains_key(name) => self.parse_macro_call()?,
+            Some(Token::Directive(directive)) => self.parse_fill(directive)?,
+            Some(_) => self.parse_instruction()?,
+            None => unreachable!()
+        }
+        if !nested {
+            if fitted && !self.fits_in_memory() {
+                return Err(self.error_at_span(self.tokenizer.span_from(start), "segment runs past 0xffff".to_string()));
+            }
+            self.finish_record(record);
+        }
+        self.expect_end_of_line()
+    }
+
+    /// starts the listing line of the statement at the current token
+    fn start_record(&mut self) -> usize {
+        let start = self.tokenizer.span_at(0).start;
+        let file = &self.files[self.file];
+        let line_start = file.text[..start].rfind('\n').map_or(0, |n| n + 1);
+        let line_end = file.text[start..].find('\n').map_or(file.text.len(), |n| start + n);
+        let line = ListingLine {
+            address: 0,
+            words: vec![],
+            file: file.path.as_ref().map(|path| path.display().to_string()),
+            line: file.text[..start].matches('\n').count() + 1 + file.line_offset,
+            column: file.text[line_start..start].chars().count() + 1,
+            text: file.text[line_start..line_end].trim().to_string()
+        };
+        let segment = self.segments.len() - 1;
+        let offset = self.segments[segment].words.len();
+        self.records.push(LineRecord { line, segment, offset, len: 0 });
+        self.records.len() - 1
+    }
+
+    /// counts the words assembled for the statement, after `.org` it belongs to the new segment
+    fn finish_record(&mut self, index: usize) {
+        let segment = self.segments.len() - 1;
+        let record = &mut self.records[index];
+        if record.segment != segment {
+            record.segment = segment;
+            record.offset = 0;
+        }
+        record.len = self.segments[segment].words.len().saturating_sub(record.offset);
+    }
+
+    fn build_listing(&mut self) {
+        let segments = &self.segments;
+        let lines = mem::take(&mut self.records).into_iter().map(|record| {
+            let segment = &segments[record.segment];
+            let mut line = record.line;
+            line.address = segment.origin.wrapping_add(record.offset as u16);
+            line.words = segment.words[record.offset..record.offset + record.len].to_vec();
+            line
+        });
+        self.listing = Listing { lines: lines.collect() };
+    }
+
+    /// parses lines up to the end of the source, recovering after errors
+    fn parse_lines(&mut self) {
         self.skip_whitesigns();
         while self.tokenizer.token_at(0).is_some() {
-            result.push(self.parse_expression());
+            if let Err(err) = self.parse_line() {
+                self.errors.push(err);
+                self.skip_line();
+            }
             self.skip_whitesigns();
         }
-        result
+        for condition in mem::take(&mut self.conditions) {
+            let err = self.error_at_span(condition.span, "missing `.endif`".to_string());
+            self.errors.push(err);
+        }
     }
+
+    /// assembles the whole source, collecting every error found on the way
+    pub fn parse(&mut self) -> Result<Image, Vec<AssembleError>> {
+        self.parse_lines();
+        self.check_overlap();
+        self.resolve_fixups();
+        self.build_listing();
+        match self.errors.is_empty() {
+            true => Ok(Image {
+                segments: mem::take(&mut self.segments).into_iter().filter(|s| !s.words.is_empty()).collect()
+            }),
+            false => Err(mem::take(&mut self.errors))
+        }
+    }
+}
+
+/// names written with `@` in the source, which could clash with renamed macro locals
+fn is_reserved_name(text: &str) -> bool {
+    text.contains('@') && !text.starts_with('"') && !text.starts_with('\'')
+}
+
+fn reserved_name_message(text: &str) -> String {
+    format!("`{}` is not a valid name, `@` is reserved for labels local to macros", text)
+}
+
+/// replaces names in source text, like parameters of a macro with the arguments of a call
+fn substitute(text: &str, names: &[String], values: &[String]) -> String {
+    let mut tokenizer = LookaheadTokenizer::with_mangled_names(text);
+    let mut result = String::new();
+    while let Some(token) = tokenizer.token_at(0) {
+        let span = tokenizer.span_at(0);
+        match token {
+            Token::Value(Value::Name(name)) => match names.iter().position(|n| n == name) {
+                Some(i) => result.push_str(&values[i]),
+                None => result.push_str(name)
+            },
+            _ => result.push_str(&text[span.start..span.end])
+        }
+        tokenizer.advance(1);
+    }
+    result
 }
 
 #[test]
-fn test_parse_expression() {
+fn test_parse_instruction() {
     let mut parser = Parser::new("SET A, 30");
-    assert_eq!(parser.parse_expression(), 0xfc01);
+    parser.parse_instruction().unwrap();
+    assert_eq!(parser.segments, [Segment { origin: 0, words: vec![0xfc01] }]);
 }
 
 #[test]
 fn test_parse() {
     let mut parser = Parser::new("SET A, 30");
-    assert_eq!(parser.parse(), [0xfc01]);
+    assert_eq!(parser.parse().unwrap().to_vec(), [0xfc01]);
 }
 
 #[test]
 fn test_parse2() {
     let mut parser = Parser::new("\nSET   A,  30\n\n");
-    assert_eq!(parser.parse(), [0xfc01]);
+    assert_eq!(parser.parse().unwrap().to_vec(), [0xfc01]);
 }
 
 #[test]
 fn test_parse3() {
     let mut parser = Parser::new("SET A, 30\n
                                   SET A, 1");
-    assert_eq!(parser.parse(), [
+    assert_eq!(parser.parse().unwrap().to_vec(), [
                0xfc01, // SET A, 30
                0x8801  // SET A, 1
     ]);
 }
+
+#[test]
+fn test_parse_labels() {
+    let mut parser = Parser::new(":start SET A, 1\n
+                                  loop: ADD A, 1\n
+                                  JSR end\n
+                                  SET B, loop\n
+                                  :end SET C, start");
+    assert_eq!(parser.parse().unwrap().to_vec(), [
+               0x8801,          // SET A, 1
+               0x8802,          // ADD A, 1
+               0x7c20, 0x0006,  // JSR end
+               0x7c21, 0x0001,  // SET B, loop
+               0x7c41, 0x0000   // SET C, start
+    ]);
+}
+
+#[test]
+fn test_parse_label_line() {
+    let mut parser = Parser::new("SET A, 1\n
+                                  :loop\n
+                                  again:\n
+                                  SET A, loop");
+    parser.parse().unwrap();
+    assert_eq!(parser.labels.get("loop"), Some(&1));
+    assert_eq!(parser.labels.get("again"), Some(&1));
+}
+
+#[test]
+fn test_parse_indirect() {
+    let mut parser = Parser::new("SET [A], 1\n
+                                  SET B, [J]\n
+                                  SET [B+0x10], A\n
+                                  SET [0x10 + B], A\n
+                                  SET [0x8000], 5\n
+                                  SET A, [SP]\n
+                                  SET A, [SP+3]\n
+                                  :data SET [data], [A + data]");
+    assert_eq!(parser.parse().unwrap().to_vec(), [
+               0x8901,          // SET [A], 1
+               0x3c21,          // SET B, [J]
+               0x0221, 0x0010,  // SET [B+0x10], A
+               0x0221, 0x0010,  // SET [B+0x10], A
+               0x9bc1, 0x8000,  // SET [0x8000], 5
+               0x6401,          // SET A, PEEK
+               0x6801, 0x0003,  // SET A, PICK 3
+               0x43c1, 0x000b, 0x000b   // SET [data], [A + data]
+    ]);
+}
+
+#[test]
+fn test_parse_stack() {
+    let mut parser = Parser::new("SET PUSH, A\n
+                                  SET A, POP\n
+                                  SET PEEK, 1\n
+                                  SET PICK 2, PICK 3\n
+                                  SET SP, EX\n
+                                  SET PC, POP");
+    assert_eq!(parser.parse().unwrap().to_vec(), [
+               0x0301,                  // SET PUSH, A
+               0x6001,                  // SET A, POP
+               0x8b21,                  // SET PEEK, 1
+               0x6b41, 0x0003, 0x0002,  // SET PICK 2, PICK 3
+               0x7761,                  // SET SP, EX
+               0x6381                   // SET PC, POP
+    ]);
+}
+
+#[test]
+fn test_parse_push_as_a() {
+    let mut parser = Parser::new("SET A, PUSH");
+    let errors = parser.parse().unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "PUSH can only be used as b");
+    assert_eq!((errors[0].line, errors[0].column), (1, 8));
+}
+
+#[test]
+fn test_parse_pop_as_b() {
+    let mut parser = Parser::new("SET POP, A");
+    let errors = parser.parse().unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "POP can only be used as a");
+}
+
+#[test]
+fn test_parse_literals() {
+    let mut parser = Parser::new("SET A, 30\n
+                                  SET A, 31\n
+                                  SET A, 65535\n
+                                  SET A, 0x1234\n
+                                  SET A, -2\n
+                                  SET A, 0xffff\n
+                                  IFE 1, 0\n
+                                  SET [0x1000], 0x1234");
+    assert_eq!(parser.parse().unwrap().to_vec(), [
+               0xfc01,                  // SET A, 30
+               0x7c01, 0x001f,          // SET A, 31
+               0x8001,                  // SET A, -1
+               0x7c01, 0x1234,          // SET A, 0x1234
+               0x7c01, 0xfffe,          // SET A, -2
+               0x8001,                  // SET A, 0xffff
+               0x87f2, 0x0001,          // IFE 1, 0
+               0x7fc1, 0x1234, 0x1000   // SET [0x1000], 0x1234
+    ]);
+}
+
+#[test]
+fn test_parse_errors() {
+    let mut parser = Parser::new("SET A, 1\n\
+                                  FOO A, 1\n\
+                                  SET A 1\n\
+                                  :loop SET B, [C+PC]\n\
+                                  loop: SET B, 1 2\n\
+                                  JSR nowhere\n\
+                                  SET A, ?\n\
+                                  SET A, 0x10000");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [
+               (2, "unknown instruction `FOO`"),
+               (3, "expected `,`"),
+               (4, "expected number or label, register is already given"),
+               (5, "label `loop` is already defined"),
+               (7, "expected value, found invalid token `?`"),
+               (8, "number `0x10000` does not fit into 16 bits"),
+               (6, "label `nowhere` is not defined")
+    ]);
+    assert_eq!(format!("{}", errors[6]), "6:5: label `nowhere` is not defined\nJSR nowhere\n    ^^^^^^^");
+}
+
+#[test]
+fn test_parse_data() {
+    let mut parser = Parser::new("; data follows\n\
+                                  start: DAT 1, 65535, start ; comment\n\
+                                  .dw 'a', \"hi\", \"ab\\n\"z\n\
+                                  dat \"abc\"p, \"ab\"pz, end\n\
+                                  end: SET A, '\\''");
+    assert_eq!(parser.parse().unwrap().to_vec(), [
+               0x0001, 0xffff, 0x0000,
+               0x0061, 0x0068, 0x0069, 0x0061, 0x0062, 0x000a, 0x0000,
+               0x6162, 0x6300, 0x6162, 0x0000, 0x000f,
+               0x7c01, 0x0027
+    ]);
+}
+
+#[test]
+fn test_parse_data_errors() {
+    let mut parser = Parser::new("DAT\n\
+                                  DAT 1 2\n\
+                                  DAT \"\u{142}\"p\n\
+                                  DAT \"abc\n\
+                                  SET A, \"abc\"");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [
+               (1, "expected number, label or string"),
+               (2, "expected end of line"),
+               (3, "character `\u{142}` does not fit into a packed string"),
+               (4, "expected number, label or string, found invalid token `\"abc`"),
+               (5, "expected value")
+    ]);
+}
+
+#[test]
+fn test_parse_expressions() {
+    let mut parser = Parser::new("start: SET A, (1 << 5) | 3\n\
+                                  SET B, 2 * 3 - 7\n\
+                                  SET [table + 4], 1\n\
+                                  SET C, [B + 3 * 2]\n\
+                                  SET C, [2 * 3 + B]\n\
+                                  SET C, [B - 1]\n\
+                                  SET PUSH, end - start\n\
+                                  DAT table + 1, ~0xff00 & 0xfff0 ^ 1\n\
+                                  table: end:");
+    assert_eq!(parser.parse().unwrap().to_vec(), [
+               0x7c01, 0x0023,          // SET A, 35
+               0x8021,                  // SET B, -1
+               0x8bc1, 0x0013,          // SET [19], 1
+               0x4441, 0x0006,          // SET C, [B + 6]
+               0x4441, 0x0006,          // SET C, [B + 6]
+               0x4441, 0xffff,          // SET C, [B + -1]
+               0x7f01, 0x000f,          // SET PUSH, 15
+               0x0010, 0x00f1
+    ]);
+}
+
+#[test]
+fn test_parse_expression_errors() {
+    let mut parser = Parser::new("SET A, 1 / 0\n\
+                                  SET A, 0x8000 * 2\n\
+                                  SET A, (1 + 2\n\
+                                  SET A, missing * 2\n\
+                                  end: DAT 1 / (end - end)\n\
+                                  DAT 256 * 256 * 256 * 256 * 256 * 256 * 256 * 256\n\
+                                  SET A, 1 << 100");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [
+               (1, "division by zero"),
+               (2, "value `65536` does not fit into 16 bits"),
+               (3, "expected `)`"),
+               (6, "value does not fit into 16 bits"),
+               (7, "value does not fit into 16 bits"),
+               (4, "label `missing` is not defined"),
+               (5, "division by zero")
+    ]);
+    assert_eq!(errors[6].snippet, "end: DAT 1 / (end - end)\n         ^^^^^^^^^^^^^^^");
+}
+
+#[test]
+fn test_parse_directives() {
+    let mut parser = Parser::new(".equ WIDTH, 32\n\
+                                  #define HEIGHT WIDTH / 2\n\
+                                  SET A, HEIGHT\n\
+                                  SET B, WIDTH\n\
+                                  .org 0x100\n\
+                                  start: .reserve 2\n\
+                                  .fill 3, 0xabcd\n\
+                                  .align 4\n\
+                                  end: DAT start, end, WIDTH * HEIGHT\n\
+                                  .org end + 0x10\n\
+                                  SET PC, start");
+    assert_eq!(parser.parse().unwrap().segments, [
+               Segment { origin: 0, words: vec![0xc401, 0x7c21, 0x0020] },
+               Segment { origin: 0x100, words: vec![0, 0, 0xabcd, 0xabcd, 0xabcd, 0, 0, 0, 0x0100, 0x0108, 0x0200] },
+               Segment { origin: 0x118, words: vec![0x7f81, 0x0100] }
+    ]);
+}
+
+#[test]
+fn test_parse_directive_errors() {
+    let mut parser = Parser::new(".equ A, 1\n\
+                                  .equ X1, later\n\
+                                  .align 0\n\
+                                  .fill 2 3\n\
+                                  .equ Y1 1\n\
+                                  Y1: SET A, 1\n\
+                                  later:");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [
+               (1, "expected name"),
+               (2, "label `later` is not defined"),
+               (3, "alignment has to be greater than zero"),
+               (4, "expected `,`"),
+               (6, "constant `Y1` is already defined")
+    ]);
+}
+
+#[test]
+fn test_parse_segment_past_end() {
+    let mut parser = Parser::new(".reserve 0xffff\n\
+                                  .reserve 2");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [(2, "segment runs past 0xffff")]);
+    let mut parser = Parser::new(".org 0xffff\n\
+                                  DAT 1\n\
+                                  DAT 2, 3");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [(3, "segment runs past 0xffff")]);
+    assert_eq!(errors[0].snippet, "DAT 2, 3\n^^^^^^^^");
+}
+
+#[test]
+fn test_parse_overlapping_segments() {
+    let mut parser = Parser::new("DAT 1, 2\n\
+                                  .org 0x10\n\
+                                  DAT 3\n\
+                                  .org 1\n\
+                                  DAT 4\n\
+                                  .org 0x0f\n\
+                                  .org 0x11\n\
+                                  DAT 5\n\
+                                  .org 0x0f\n\
+                                  DAT 6, 7");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [
+               (4, "`.org` overwrites words already assembled at 0x0001"),
+               (9, "`.org` overwrites words already assembled at 0x0010")
+    ]);
+}
+
+/// writes files into a fresh temporary directory
+#[cfg(test)]
+fn write_files(test: &str, files: &[(&str, &[u8])]) -> PathBuf {
+    let directory = ::std::env::temp_dir().join(format!("dcpu16-{}-{}", test, ::std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    for &(name, contents) in files {
+        let path = directory.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    directory
+}
+
+#[test]
+fn test_parse_include() {
+    let directory = write_files("include", &[
+        ("main.dasm", b".include \"lib/screen.dasm\"\n\
+                        SET A, WIDTH\n\
+                        JSR clear\n\
+                        .incbin \"font.bin\"\n\
+                        .incbin \"font.bin\", little"),
+        ("lib/screen.dasm", b".include \"common.dasm\"\n\
+                              clear: SET PC, POP"),
+        ("inc/common.dasm", b".equ WIDTH, 32"),
+        ("font.bin", &[0x12, 0x34, 0xab, 0xcd])
+    ]);
+    let source = fs::read_to_string(directory.join("main.dasm")).unwrap();
+    let mut parser = Parser::new(&source);
+    parser.set_file_name(directory.join("main.dasm"));
+    parser.add_include_path(directory.join("inc"));
+    assert_eq!(parser.parse().unwrap().to_vec(), [
+               0x6381,                  // SET PC, POP
+               0x7c01, 0x0020,          // SET A, 32
+               0x7c20, 0x0000,          // JSR 0
+               0x1234, 0xabcd,
+               0x3412, 0xcdab
+    ]);
+}
+
+#[test]
+fn test_parse_include_errors() {
+    let directory = write_files("include-errors", &[
+        ("main.dasm", b".include \"missing.dasm\"\n\
+                        .include \"a.dasm\"\n\
+                        .incbin \"odd.bin\"\n\
+                        SET A, 1"),
+        ("a.dasm", b".include \"b.dasm\"\n\
+                     SET A, nowhere"),
+        ("b.dasm", b"SET X,\n\
+                     .include \"a.dasm\""),
+        ("odd.bin", &[1, 2, 3])
+    ]);
+    let source = fs::read_to_string(directory.join("main.dasm")).unwrap();
+    let mut parser = Parser::new(&source);
+    parser.set_file_name(directory.join("main.dasm"));
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(String, usize, &str)> = errors.iter().map(|err| {
+        let path = PathBuf::from(err.file.as_ref().unwrap());
+        (path.file_name().unwrap().to_string_lossy().into_owned(), err.line, &err.message[..])
+    }).collect();
+    assert_eq!(messages, [
+               ("main.dasm".to_string(), 1, "cannot find `missing.dasm`"),
+               ("b.dasm".to_string(), 1, "expected value"),
+               ("b.dasm".to_string(), 2, "`a.dasm` includes itself"),
+               ("main.dasm".to_string(), 3, "cannot read `odd.bin`: binary image has an odd number of bytes"),
+               ("a.dasm".to_string(), 2, "label `nowhere` is not defined")
+    ]);
+}
+
+#[test]
+fn test_parse_macros() {
+    let mut parser = Parser::new(".macro push_all()\n\
+                                  SET PUSH, A\n\
+                                  SET PUSH, B\n\
+                                  .endmacro\n\
+                                  .macro wait(port, value)\n\
+                                  loop: IFN [port], value\n\
+                                  SET PC, loop\n\
+                                  .endmacro\n\
+                                  .macro twice(register)\n\
+                                  ADD register, 1\n\
+                                  ADD register, 1\n\
+                                  .endmacro\n\
+                                  .macro nested()\n\
+                                  again: twice(A)\n\
+                                  wait(again, 2) ; refers to the label of this expansion\n\
+                                  SET PC, again\n\
+                                  .endmacro\n\
+                                  push_all\n\
+                                  wait(0x1000, 3)\n\
+                                  nested()");
+    assert_eq!(parser.parse().unwrap().to_vec(), [
+               0x0301, 0x0701,          // SET PUSH, A / SET PUSH, B
+               0x93d3, 0x1000,          // IFN [0x1000], 3
+               0x7f81, 0x0002,          // SET PC, 2
+               0x8802, 0x8802,          // ADD A, 1 / ADD A, 1
+               0x8fd3, 0x0006,          // IFN [6], 2
+               0x7f81, 0x0008,          // SET PC, 8
+               0x7f81, 0x0006           // SET PC, 6
+    ]);
+    assert_eq!(parser.labels.get("loop"), None);
+    assert_eq!(parser.labels.len(), 3);
+}
+
+#[test]
+fn test_parse_macro_errors() {
+    let mut parser = Parser::new(".macro set_a(value)\n\
+                                  SET A, value\n\
+                                  SET A value\n\
+                                  .endmacro\n\
+                                  set_a(1)\n\
+                                  set_a(1, 2)\n\
+                                  .endmacro\n\
+                                  .macro set_a\n\
+                                  .endmacro\n\
+                                  .macro forever()\n\
+                                  forever()\n\
+                                  .endmacro\n\
+                                  forever");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [
+               (3, "expected `,`"),
+               (6, "macro `set_a` takes 1 arguments, found 2"),
+               (7, "`.endmacro` without `.macro`"),
+               (8, "macro `set_a` is already defined"),
+               (11, "macro `forever` is expanded too deeply")
+    ]);
+    assert_eq!(format!("{}", errors[0]), "3:7: expected `,`\nSET A 1\n      ^\n\
+                                          5:1: in expansion of macro `set_a`\nset_a(1)\n^^^^^^^^");
+}
+
+#[test]
+fn test_parse_macro_local_names() {
+    let mut parser = Parser::new(".macro wait(label)\n\
+                                  loop: SUB A, 1\n\
+                                  IFN A, 0\n\
+                                  SET PC, loop\n\
+                                  SET PC, label\n\
+                                  .endmacro\n\
+                                  wait(loop@1)\n\
+                                  loop@1: SET PC, 0\n\
+                                  .macro bad()\n\
+                                  SET PC, x@2\n\
+                                  .endmacro");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [
+               (7, "`loop@1` is not a valid name, `@` is reserved for labels local to macros"),
+               (8, "`loop@1` is not a valid name, `@` is reserved for labels local to macros"),
+               (10, "`x@2` is not a valid name, `@` is reserved for labels local to macros")
+    ]);
+}
+
+#[test]
+fn test_parse_local_labels() {
+    let mut parser = Parser::new("main: SET A, 0\n\
+                                  .loop: ADD A, 1\n\
+                                  IFN A, 5\n\
+                                  SET PC, .loop\n\
+                                  other: SET B, 0\n\
+                                  .loop: ADD B, 1\n\
+                                  IFN B, 5\n\
+                                  SET PC, .loop\n\
+                                  SET PC, main.loop\n\
+                                  1: SET C, 1\n\
+                                  IFE C, 1\n\
+                                  SET PC, 1f\n\
+                                  SET PC, 1b\n\
+                                  1: SET PC, 1b");
+    assert_eq!(parser.parse().unwrap().to_vec(), [
+               0x8401, 0x8802, 0x9813,  // SET A, 0 / ADD A, 1 / IFN A, 5
+               0x7f81, 0x0001,          // SET PC, main.loop
+               0x8421, 0x8822, 0x9833,  // SET B, 0 / ADD B, 1 / IFN B, 5
+               0x7f81, 0x0006,          // SET PC, other.loop
+               0x7f81, 0x0001,          // SET PC, main.loop
+               0x8841, 0x8852,          // SET C, 1 / IFE C, 1
+               0x7f81, 0x0012,          // SET PC, 1f
+               0x7f81, 0x000c,          // SET PC, 1b
+               0x7f81, 0x0012           // SET PC, 1b
+    ]);
+    assert_eq!(parser.labels.get("main.loop"), Some(&1));
+    assert_eq!(parser.labels.get("other.loop"), Some(&6));
+}
+
+#[test]
+fn test_parse_local_label_errors() {
+    let mut parser = Parser::new("SET PC, 2b\n\
+                                  a1:\n\
+                                  .x:\n\
+                                  .x:\n\
+                                  SET PC, 3f");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [
+               (4, "label `a1.x` is already defined"),
+               (1, "label `2b` is not defined"),
+               (5, "label `3f` is not defined")
+    ]);
+}
+
+#[test]
+fn test_parse_conditions() {
+    let mut parser = Parser::new(".equ VERSION, 2\n\
+                                  .if VERSION - 1\n\
+                                  SET A, 1\n\
+                                  .else\n\
+                                  SET A, 2\n\
+                                  .endif\n\
+                                  .ifdef MONITOR\n\
+                                  SET B, 1\n\
+                                  .ifndef KEYBOARD\n\
+                                  SET C, 1\n\
+                                  .else\n\
+                                  SET C, 2\n\
+                                  .endif\n\
+                                  .else\n\
+                                  SET B, 2\n\
+                                  .if undefined / 0\n\
+                                  .endif\n\
+                                  .endif\n\
+                                  .if 0\n\
+                                  inside: SET X, 1\n\
+                                  .endif\n\
+                                  .ifndef inside\n\
+                                  SET Y, 1\n\
+                                  .endif");
+    parser.define("MONITOR", 1);
+    assert_eq!(parser.parse().unwrap().to_vec(), [0x8801, 0x8821, 0x8841, 0x8881]);
+    assert_eq!(parser.labels.get("inside"), None);
+}
+
+#[test]
+fn test_parse_conditions_after_labels() {
+    let mut parser = Parser::new("start:\n\
+                                  .ifdef FOO\n\
+                                  SET A, 1\n\
+                                  .endif\n\
+                                  first:\n\
+                                  .if 1\n\
+                                  SET B, 1\n\
+                                  second:\n\
+                                  .else\n\
+                                  SET B, 2\n\
+                                  .endif\n\
+                                  .if 1\n\
+                                  third:\n\
+                                  .endif\n\
+                                  fourth:\n\
+                                  .ifndef FOO\n\
+                                  SET C, 1\n\
+                                  .endif");
+    assert_eq!(parser.parse().unwrap().to_vec(), [0x8821, 0x8841]);
+    let labels: Vec<Option<&u16>> = ["start", "first", "second", "third", "fourth"].iter()
+        .map(|&name| parser.labels.get(name))
+        .collect();
+    assert_eq!(labels, [Some(&0), Some(&0), Some(&1), Some(&1), Some(&1)]);
+}
+
+#[test]
+fn test_parse_condition_errors() {
+    let mut parser = Parser::new(".else\n\
+                                  .endif\n\
+                                  .if 1\n\
+                                  .else\n\
+                                  .else\n\
+                                  .endif\n\
+                                  .if missing\n\
+                                  SET A, 1\n\
+                                  .endif\n\
+                                  .ifdef 1\n\
+                                  .endif\n\
+                                  .if 1\n\
+                                  end: .endif");
+    let errors = parser.parse().unwrap_err();
+    let messages: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, &err.message[..])).collect();
+    assert_eq!(messages, [
+               (1, "`.else` without `.if`"),
+               (2, "`.endif` without `.if`"),
+               (5, "`.else` after `.else`"),
+               (7, "label `missing` is not defined"),
+               (10, "expected name"),
+               (13, "conditional directives have to be on a line of their own"),
+               (12, "missing `.endif`")
+    ]);
+}
+
+#[test]
+fn test_parse_listing() {
+    let mut parser = Parser::new(".equ WIDTH, 32\n\
+                                  .macro clear(register)\n\
+                                  SET register, 0\n\
+                                  .endmacro\n\
+                                  main: SET A, WIDTH\n\
+                                  clear(B)\n\
+                                  .org 0x10\n\
+                                  data: DAT \"hello\" ; greeting");
+    parser.parse().unwrap();
+    let lines: Vec<(u16, &[u16], usize, &str)> = parser.listing().lines.iter()
+        .map(|line| (line.address, &line.words[..], line.line, &line.text[..]))
+        .collect();
+    assert_eq!(lines, [
+               (0x0000, &[][..], 1, ".equ WIDTH, 32"),
+               (0x0000, &[][..], 2, ".macro clear(register)"),
+               (0x0000, &[0x7c01, 0x0020][..], 5, "main: SET A, WIDTH"),
+               (0x0002, &[][..], 6, "clear(B)"),
+               (0x0002, &[0x8421][..], 3, "SET B, 0"),
+               (0x0010, &[][..], 7, ".org 0x10"),
+               (0x0010, &[0x68, 0x65, 0x6c, 0x6c, 0x6f][..], 8, "data: DAT \"hello\" ; greeting")
+    ]);
+    assert_eq!(parser.symbol_map().to_text(), "0000  label     main\n\
+                                               0010  label     data\n\
+                                               0020  constant  WIDTH\n");
+}
+
+#[test]
+fn test_parse_debug_info() {
+    let mut parser = Parser::new(".equ COUNT, 3\n\
+                                  main:\n    SET A, COUNT\n\
+                                  loop: SUB A, 1\n\
+                                  .loop: IFN A, 0\n\
+                                  \tSET PC, loop");
+    parser.set_file_name("main.dasm");
+    parser.parse().unwrap();
+    let info = parser.debug_info();
+    let lines: Vec<(u16, u16, String)> = info.lines.iter()
+        .map(|entry| (entry.address, entry.len, entry.location.to_string()))
+        .collect();
+    assert_eq!(lines, [
+               (0, 1, "main.dasm:3:5".to_string()),
+               (1, 1, "main.dasm:4:7".to_string()),
+               (2, 1, "main.dasm:5:8".to_string()),
+               (3, 2, "main.dasm:6:2".to_string())
+    ]);
+    let labels: Vec<(&str, u16)> = info.labels.iter().map(|label| (&label.name[..], label.address)).collect();
+    assert_eq!(labels, [("main", 0), ("loop", 1), ("loop.loop", 2)]);
+    assert_eq!(info.describe(4), "main.dasm:6:2 (loop.loop+2)");
+    assert_eq!(info.address_of_line(Some("main.dasm"), 5), Some(2));
+}
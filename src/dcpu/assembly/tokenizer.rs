@@ -1,10 +1,12 @@
 #![allow(dead_code)]
+use super::error::Span as Span;
+
 pub trait Bits {
     fn to_bits(&self) -> u8;
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
-enum Opcode {
+pub enum Opcode {
     SET,
     ADD,
     SUB,
@@ -43,6 +45,15 @@ enum Opcode {
     HWI
 }
 
+impl Opcode {
+    /// special opcodes take a single value
+    pub fn is_special(&self) -> bool {
+        matches!(*self, Opcode::JSR | Opcode::INT | Opcode::IAG | Opcode::IAS |
+                        Opcode::RFI | Opcode::IAQ | Opcode::HWN | Opcode::HWQ |
+                        Opcode::HWI)
+    }
+}
+
 impl Bits for Opcode {
     fn to_bits(&self) -> u8 {
         match *self {
@@ -88,8 +99,21 @@ impl Bits for Opcode {
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
-enum Constant {
-    A, B, C, X, Y, Z, I, J
+pub enum Constant {
+    A, B, C, X, Y, Z, I, J,
+    SP, PC, EX,
+    PUSH,   // only in b
+    POP,    // only in a
+    PEEK,
+    PICK    // followed by a number
+}
+
+impl Constant {
+    /// general purpose registers A - J
+    pub fn is_general(&self) -> bool {
+        matches!(*self, Constant::A | Constant::B | Constant::C | Constant::X |
+                        Constant::Y | Constant::Z | Constant::I | Constant::J)
+    }
 }
 
 impl Bits for Constant {
@@ -103,57 +127,134 @@ impl Bits for Constant {
             Constant::Z => 0x5,
             Constant::I => 0x6,
             Constant::J => 0x7,
+            Constant::PUSH => 0x18,
+            Constant::POP => 0x18,
+            Constant::PEEK => 0x19,
+            Constant::PICK => 0x1a,
+            Constant::SP => 0x1b,
+            Constant::PC => 0x1c,
+            Constant::EX => 0x1d,
         }
     }
 }
 
+/// which definition of a numeric label a reference means
 #[derive(Debug, PartialEq, Copy, Clone)]
-enum Value<'a> {
+pub enum Direction {
+    Backward,   // `1b`, the closest one above
+    Forward     // `1f`, the closest one below
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Value<'a> {
     Number(u16),
     Name(&'a str),
-    Constant(Constant)
+    Constant(Constant),
+    NumericLabel(u16, Direction)
 }
 
-impl<'a> Bits for Value<'a> {
-    fn to_bits(&self) -> u8 {
-        match *self {
-            Value::Number(n) => (n + 0x21) as u8,
-            Value::Constant(c) => c.to_bits(),
-            _ => panic!()
+/// operators of constant expressions, `~` is the only one which is unary only
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Not
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Directive {
+    Dat,        // DAT or .dw, emits data words
+    Org,        // continues at the given address
+    Reserve,    // emits the given number of zeros
+    Fill,       // emits a word the given number of times
+    Align,      // emits zeros until the address is a multiple of the given number
+    Equ,        // .equ or #define, names a constant
+    Include,    // assembles another source file in place
+    Incbin,     // emits the words of a binary file
+    Macro,      // starts a macro definition
+    Endmacro,
+    If,         // assembles the following lines only if the value is not zero
+    Ifdef,      // ... only if the symbol is defined
+    Ifndef,     // ... only if the symbol is not defined
+    Else,
+    Endif
+}
+
+/// string literal, `"text"` optionally followed by `p` to pack two characters
+/// into a word and `z` to terminate it with zero
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Text<'a> {
+    pub raw: &'a str,           // between the quotes, escapes are not replaced
+    pub packed: bool,
+    pub zero_terminated: bool
+}
+
+impl<'a> Text<'a> {
+    /// characters of the string with escapes replaced
+    pub fn chars(&self) -> Vec<char> {
+        let mut result = vec![];
+        let mut chars = self.raw.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => result.push(chars.next().and_then(unescape).unwrap()),
+                c => result.push(c)
+            }
         }
+        result
+    }
+}
+
+/// character written after a backslash
+fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        '0' => Some('\0'),
+        '\\' | '\'' | '"' => Some(c),
+        _ => None
     }
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Token<'a> {
     Opcode(Opcode),
+    Directive(Directive),
     Value(Value<'a>),
+    String(Text<'a>),
     Whitespace,
     Comma,
+    Colon,
+    OpenBracket,
+    CloseBracket,
+    OpenParen,
+    CloseParen,
+    Operator(Operator),
     Endline,
-    Invalid(&'a str, usize)
-}
-
-impl<'a> Bits for Token<'a> {
-    fn to_bits(&self) -> u8 {
-        match *self {
-            Token::Opcode(op) => op.to_bits(),
-            Token::Value(v) => v.to_bits(),
-            _ => panic!()
-        }
-    }
+    Invalid(&'a str, usize),
+    Overflow(&'a str, usize)    // number which does not fit into a word
 }
 
 struct Tokenizer<'a> {
     source: &'a str,
-    position: usize
+    position: usize,
+    mangled_names: bool     // names may contain `@`, only in text generated for macro expansions
 }
 
 impl<'a> Tokenizer<'a> {
-    pub fn new(source: &str) -> Tokenizer {
+    pub fn new(source: &'a str) -> Tokenizer<'a> {
         Tokenizer {
-            source: source,
-            position: 0
+            source,
+            position: 0,
+            mangled_names: false
         }
     }
 
@@ -173,14 +274,54 @@ impl<'a> Tokenizer<'a> {
                 self.advance(1);
                 Some(Token::Comma)
             },
-            '0' if self.has_at_least(1) && self.char_at(1) == 'x' => {
-                self.advance(2); // consume 0x
-                Some(self.consume_number(16))
+            ':' => {
+                self.advance(1);
+                Some(Token::Colon)
+            },
+            '[' => {
+                self.advance(1);
+                Some(Token::OpenBracket)
+            },
+            ']' => {
+                self.advance(1);
+                Some(Token::CloseBracket)
+            },
+            '(' => {
+                self.advance(1);
+                Some(Token::OpenParen)
             },
-            '0'...'9' => Some(self.consume_number(10)),
-            'a'...'z' | 'A'...'Z' => Some(self.consume_word()),
-            '-' => None,
-            _ => None
+            ')' => {
+                self.advance(1);
+                Some(Token::CloseParen)
+            },
+            '<' | '>' if self.has_at_least(1) && self.char_at(1) == c => {
+                self.advance(2);
+                Some(Token::Operator(if c == '<' { Operator::Shl } else { Operator::Shr }))
+            },
+            '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '~' => {
+                self.advance(1);
+                Some(Token::Operator(match c {
+                    '+' => Operator::Add,
+                    '-' => Operator::Sub,
+                    '*' => Operator::Mul,
+                    '/' => Operator::Div,
+                    '%' => Operator::Mod,
+                    '&' => Operator::And,
+                    '|' => Operator::Or,
+                    '^' => Operator::Xor,
+                    _ => Operator::Not
+                }))
+            },
+            '0'..='9' => Some(self.consume_number()),
+            'a'..='z' | 'A'..='Z' | '.' | '#' => Some(self.consume_word()),
+            ';' => Some(self.consume_comment()),
+            '"' => Some(self.consume_string()),
+            '\'' => Some(self.consume_char()),
+            _ => {
+                let start_position = self.position;
+                self.advance(c.len_utf8());
+                Some(Token::Invalid(self.slice_from(start_position), start_position))
+            }
         }
     }
     
@@ -225,32 +366,146 @@ impl<'a> Tokenizer<'a> {
         Token::Whitespace
     }
 
-    fn consume_number(&mut self, radix: u32) -> Token<'a> {
+    /// comments last until the end of the line and are treated as whitespace
+    fn consume_comment(&mut self) -> Token<'a> {
+        while !self.is_eof() && self.next_char() != '\n' {
+            let c = self.next_char();
+            self.advance(c.len_utf8());
+        }
+        Token::Whitespace
+    }
+
+    /// consumes a single, possibly escaped, character of a string or character literal
+    fn consume_escaped(&mut self) -> Option<char> {
+        if self.is_eof() || self.next_char() == '\n' {
+            return None;
+        }
+        let c = self.next_char();
+        self.advance(c.len_utf8());
+        match c {
+            '\\' if !self.is_eof() && self.next_char() != '\n' => {
+                let e = self.next_char();
+                self.advance(e.len_utf8());
+                unescape(e)
+            },
+            '\\' => None,
+            c => Some(c)
+        }
+    }
+
+    fn consume_string(&mut self) -> Token<'a> {
         let start_position = self.position;
-        while !self.is_eof() {
-            match self.next_char() {
-                '0'...'9' => self.advance(1),
-                _ => break
+        self.advance(1);
+        while self.is_eof() || self.next_char() != '"' {
+            if self.consume_escaped().is_none() {
+                return Token::Invalid(self.slice_from(start_position), start_position);
             }
         }
-        
+        let raw = &self.source[start_position + 1..self.position];
+        self.advance(1);
+
+        let flags_position = self.position;
+        while !self.is_eof() && matches!(self.next_char(), 'a'..='z' | 'A'..='Z' | '0'..='9' | '_') {
+            self.advance(1);
+        }
+        let (packed, zero_terminated) = match self.slice_from(flags_position) {
+            "" => (false, false),
+            "p" => (true, false),
+            "z" => (false, true),
+            "pz" | "zp" => (true, true),
+            _ => return Token::Invalid(self.slice_from(start_position), start_position)
+        };
+        Token::String(Text { raw, packed, zero_terminated })
+    }
+
+    fn consume_char(&mut self) -> Token<'a> {
+        let start_position = self.position;
+        self.advance(1);
+        let c = match self.is_eof() || self.next_char() == '\'' {
+            true => None,
+            false => self.consume_escaped()
+        };
+        match c {
+            Some(c) if !self.is_eof() && self.next_char() == '\'' && (c as u32) <= 0xffff => {
+                self.advance(1);
+                Token::Value(Value::Number(c as u16))
+            },
+            _ => Token::Invalid(self.slice_from(start_position), start_position)
+        }
+    }
+
+    /// consumes a number with an optional 0x, 0b or 0o prefix,
+    /// digits may be separated with underscores
+    fn consume_number(&mut self) -> Token<'a> {
+        let start_position = self.position;
+        let prefix = match self.has_at_least(1) {
+            true => (self.next_char(), self.char_at(1)),
+            false => (self.next_char(), ' ')
+        };
+        let radix = match prefix {
+            ('0', 'x') | ('0', 'X') => 16,
+            ('0', 'b') | ('0', 'B') => 2,
+            ('0', 'o') | ('0', 'O') => 8,
+            _ => 10
+        };
+        if radix != 10 {
+            self.advance(2); // consume the prefix
+        }
+
+        let digits_position = self.position;
+        while !self.is_eof() && matches!(self.next_char(), 'a'..='z' | 'A'..='Z' | '0'..='9' | '_') {
+            self.advance(1);
+        }
+        let digits: String = self.slice_from(digits_position).chars().filter(|&c| c != '_').collect();
         let slice = self.slice_from(start_position);
-        match u16::from_str_radix(slice, radix) {
-            Ok(v) => Token::Value(Value::Number(v)),
-            Err(_err) => Token::Invalid(slice, start_position)
+        let (number, suffix) = slice.split_at(slice.len() - 1);
+        if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) && (suffix == "b" || suffix == "f") {
+            let direction = if suffix == "b" { Direction::Backward } else { Direction::Forward };
+            return match number.parse() {
+                Ok(n) => Token::Value(Value::NumericLabel(n, direction)),
+                Err(_) => Token::Overflow(slice, start_position)
+            };
+        }
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Token::Invalid(slice, start_position);
+        }
+
+        // only too many digits can fail here
+        let value = u64::from_str_radix(&digits, radix).unwrap_or(u64::MAX);
+        match value {
+            0..=0xffff => Token::Value(Value::Number(value as u16)),
+            _ => Token::Overflow(slice, start_position)
         }
     }
 
     fn consume_word(&mut self) -> Token<'a> {
         let start_position = self.position;
+        self.advance(1); // first character is checked by next_token
         while !self.is_eof() {
             match self.next_char() {
-                'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => self.advance(1),
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '@' => self.advance(1),
                 _ => break
             }
         }
         let slice = self.slice_from(start_position);
         match slice {
+            n if n.contains('@') && !self.mangled_names => Token::Invalid(n, start_position),
+            "DAT" | "dat" | ".dw" | ".DW" => Token::Directive(Directive::Dat),
+            ".org" | ".ORG" => Token::Directive(Directive::Org),
+            ".reserve" | ".RESERVE" => Token::Directive(Directive::Reserve),
+            ".fill" | ".FILL" => Token::Directive(Directive::Fill),
+            ".align" | ".ALIGN" => Token::Directive(Directive::Align),
+            ".equ" | ".EQU" | "#define" => Token::Directive(Directive::Equ),
+            ".include" | ".INCLUDE" => Token::Directive(Directive::Include),
+            ".incbin" | ".INCBIN" => Token::Directive(Directive::Incbin),
+            ".macro" | ".MACRO" => Token::Directive(Directive::Macro),
+            ".endmacro" | ".ENDMACRO" => Token::Directive(Directive::Endmacro),
+            ".if" | ".IF" => Token::Directive(Directive::If),
+            ".ifdef" | ".IFDEF" => Token::Directive(Directive::Ifdef),
+            ".ifndef" | ".IFNDEF" => Token::Directive(Directive::Ifndef),
+            ".else" | ".ELSE" => Token::Directive(Directive::Else),
+            ".endif" | ".ENDIF" => Token::Directive(Directive::Endif),
+            n if n.starts_with('#') => Token::Invalid(n, start_position),
             "A" | "a" => Token::Value(Value::Constant(Constant::A)),
             "B" | "b" => Token::Value(Value::Constant(Constant::B)),
             "C" | "c" => Token::Value(Value::Constant(Constant::C)),
@@ -259,6 +514,13 @@ impl<'a> Tokenizer<'a> {
             "Z" | "z" => Token::Value(Value::Constant(Constant::Z)),
             "I" | "i" => Token::Value(Value::Constant(Constant::I)),
             "J" | "j" => Token::Value(Value::Constant(Constant::J)),
+            "SP" | "sp" => Token::Value(Value::Constant(Constant::SP)),
+            "PC" | "pc" => Token::Value(Value::Constant(Constant::PC)),
+            "EX" | "ex" => Token::Value(Value::Constant(Constant::EX)),
+            "PUSH" | "push" => Token::Value(Value::Constant(Constant::PUSH)),
+            "POP" | "pop" => Token::Value(Value::Constant(Constant::POP)),
+            "PEEK" | "peek" => Token::Value(Value::Constant(Constant::PEEK)),
+            "PICK" | "pick" => Token::Value(Value::Constant(Constant::PICK)),
             "SET" | "set" => Token::Opcode(Opcode::SET),
             "ADD" | "add" => Token::Opcode(Opcode::ADD),
             "SUB" | "sub" => Token::Opcode(Opcode::SUB),
@@ -296,43 +558,81 @@ impl<'a> Tokenizer<'a> {
             "HWN" | "hwn" => Token::Opcode(Opcode::HWN),
             "HWQ" | "hwq" => Token::Opcode(Opcode::HWQ),
             "HWI" | "hwi" => Token::Opcode(Opcode::HWI),
-            n @ _ => Token::Invalid(n, start_position)
+            n => Token::Value(Value::Name(n))
         }
     }
 }
 
 pub struct LookaheadTokenizer<'a> {
     tokenizer: Tokenizer<'a>,
-    cache: Vec<Option<Token<'a>>>
+    cache: Vec<Option<Token<'a>>>,
+    spans: Vec<Span>,               // where in the source cached tokens are
+    previous_end: usize             // where the last consumed token ends
 }
 
 impl<'a> LookaheadTokenizer<'a> {
-    pub fn new(source: &str) -> LookaheadTokenizer {
+    pub fn new(source: &'a str) -> LookaheadTokenizer<'a> {
         LookaheadTokenizer {
             tokenizer: Tokenizer::new(source),
-            cache: vec![]
+            cache: vec![],
+            spans: vec![],
+            previous_end: 0
         }
     }
 
+    /// tokenizer for macro expansions, where local labels are renamed to `name@expansion`
+    pub fn with_mangled_names(source: &'a str) -> LookaheadTokenizer<'a> {
+        let mut tokenizer = LookaheadTokenizer::new(source);
+        tokenizer.tokenizer.mangled_names = true;
+        tokenizer
+    }
+
+    pub fn source(&self) -> &'a str {
+        self.tokenizer.source
+    }
+
     pub fn token_at(&mut self, position: usize) -> Option<Token<'a>> {
         self.load_until(position);
         self.cache[position]
     }
 
+    /// span of the token, an empty span at the end of the source if there is no token
+    pub fn span_at(&mut self, position: usize) -> Span {
+        self.load_until(position);
+        self.spans[position]
+    }
+
+    /// span from start up to the end of the last consumed token
+    pub fn span_from(&self, start: usize) -> Span {
+        Span { start, end: self.previous_end.max(start) }
+    }
+
     fn load_until(&mut self, n: usize) {
         while self.cache.len() <= n {
+            let start = self.tokenizer.position;
             self.cache.push(self.tokenizer.next_token());
+            self.spans.push(Span { start, end: self.tokenizer.position });
         }
     }
 
     #[inline]
     pub fn advance(&mut self, n: usize) {
         for _i in 0..n {
+            self.load_until(0);
             self.cache.remove(0);
+            self.previous_end = self.spans.remove(0).end;
         }
     }
 }
 
+#[test]
+fn test_mangled_names() {
+    let mut tokenizer = Tokenizer::new("loop@1");
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("loop@1", 0)));
+    let mut tokenizer = LookaheadTokenizer::with_mangled_names("loop@1");
+    assert_eq!(tokenizer.token_at(0), Some(Token::Value(Value::Name("loop@1"))));
+}
+
 #[test]
 fn test_token() {
     let mut tokenizer = Tokenizer::new("  ,,\n0x0 0x13 13 A");
@@ -361,3 +661,222 @@ fn test_expression() {
     assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(15))));
 }
 
+
+#[test]
+fn test_label() {
+    let mut tokenizer = Tokenizer::new(":loop SET A, loop\nend:");
+    assert_eq!(tokenizer.next_token(), Some(Token::Colon));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Name("loop"))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Opcode(Opcode::SET)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::A))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Comma));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Name("loop"))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Endline));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Name("end"))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Colon));
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_brackets() {
+    let mut tokenizer = Tokenizer::new("[B+0x10] [SP]");
+    assert_eq!(tokenizer.next_token(), Some(Token::OpenBracket));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::B))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Operator(Operator::Add)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(0x10))));
+    assert_eq!(tokenizer.next_token(), Some(Token::CloseBracket));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::OpenBracket));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::SP))));
+    assert_eq!(tokenizer.next_token(), Some(Token::CloseBracket));
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_stack_keywords() {
+    let mut tokenizer = Tokenizer::new("PUSH pop PEEK PICK PC EX");
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::PUSH))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::POP))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::PEEK))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::PICK))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::PC))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::EX))));
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_invalid() {
+    let mut tokenizer = Tokenizer::new("A ?");
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::A))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("?", 2)));
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_spans() {
+    let mut tokenizer = LookaheadTokenizer::new("SET A,  15");
+    assert_eq!(tokenizer.span_at(0), Span { start: 0, end: 3 });
+    assert_eq!(tokenizer.span_at(2), Span { start: 4, end: 5 });
+    tokenizer.advance(5);
+    assert_eq!(tokenizer.span_at(0), Span { start: 8, end: 10 });
+    assert_eq!(tokenizer.span_at(1), Span { start: 10, end: 10 });
+}
+
+#[test]
+fn test_comment() {
+    let mut tokenizer = Tokenizer::new("SET A, 1 ; comment, \"with\" 'stuff'\n; another\nA");
+    assert_eq!(tokenizer.next_token(), Some(Token::Opcode(Opcode::SET)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::A))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Comma));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(1))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Endline));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Endline));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::A))));
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_data() {
+    let mut tokenizer = Tokenizer::new("DAT \"a\\\"b\"z, 'c', '\\n', .dw \"\"p");
+    assert_eq!(tokenizer.next_token(), Some(Token::Directive(Directive::Dat)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    let text = Text { raw: "a\\\"b", packed: false, zero_terminated: true };
+    assert_eq!(tokenizer.next_token(), Some(Token::String(text)));
+    assert_eq!(text.chars(), ['a', '"', 'b']);
+    assert_eq!(tokenizer.next_token(), Some(Token::Comma));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(0x63))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Comma));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(0x0a))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Comma));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Directive(Directive::Dat)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::String(Text { raw: "", packed: true, zero_terminated: false })));
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_invalid_strings() {
+    let mut tokenizer = Tokenizer::new("\"abc\n'ab\n''\n\"a\\q\"\n\"a\"x");
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("\"abc", 0)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Endline));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("'a", 5)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::B))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Endline));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("'", 9)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("'", 10)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Endline));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("\"a\\q", 12)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("\"", 16)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Endline));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("\"a\"x", 18)));
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_numbers() {
+    let mut tokenizer = Tokenizer::new("0xff 0XaB_cD 0b1010 0o777 1_000 0 0xffff");
+    let expected = [0xff, 0xabcd, 0b1010, 0o777, 1000, 0, 0xffff];
+    for (i, &n) in expected.iter().enumerate() {
+        if i > 0 {
+            assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+        }
+        assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(n))));
+    }
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_invalid_numbers() {
+    let mut tokenizer = Tokenizer::new("0x10000 65536 99999999999999999999999 0x 0b102 12ab");
+    assert_eq!(tokenizer.next_token(), Some(Token::Overflow("0x10000", 0)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Overflow("65536", 8)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Overflow("99999999999999999999999", 14)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("0x", 38)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("0b102", 41)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("12ab", 47)));
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_operators() {
+    let mut tokenizer = Tokenizer::new("(1<<5)|~-x>>2 < >");
+    assert_eq!(tokenizer.next_token(), Some(Token::OpenParen));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(1))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Operator(Operator::Shl)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(5))));
+    assert_eq!(tokenizer.next_token(), Some(Token::CloseParen));
+    assert_eq!(tokenizer.next_token(), Some(Token::Operator(Operator::Or)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Operator(Operator::Not)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Operator(Operator::Sub)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Constant(Constant::X))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Operator(Operator::Shr)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(2))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("<", 14)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid(">", 16)));
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_directives() {
+    let mut tokenizer = Tokenizer::new(".org .RESERVE .fill .align .equ #define #foo .loop");
+    assert_eq!(tokenizer.next_token(), Some(Token::Directive(Directive::Org)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Directive(Directive::Reserve)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Directive(Directive::Fill)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Directive(Directive::Align)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Directive(Directive::Equ)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Directive(Directive::Equ)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("#foo", 40)));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Name(".loop"))));
+    assert_eq!(tokenizer.next_token(), None);
+}
+
+#[test]
+fn test_numeric_labels() {
+    let mut tokenizer = Tokenizer::new("1: 1b 0b 12f 0b1 0x1f 1bf");
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(1))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Colon));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::NumericLabel(1, Direction::Backward))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::NumericLabel(0, Direction::Backward))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::NumericLabel(12, Direction::Forward))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(1))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Value(Value::Number(0x1f))));
+    assert_eq!(tokenizer.next_token(), Some(Token::Whitespace));
+    assert_eq!(tokenizer.next_token(), Some(Token::Invalid("1bf", 22)));
+    assert_eq!(tokenizer.next_token(), None);
+}
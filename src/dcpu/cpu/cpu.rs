@@ -1,30 +1,58 @@
 #![allow(dead_code)]
-use super::memory::Memory as Memory;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read};
+use std::time::Duration;
+use super::memory::{Memory, Endianness};
 use super::instruction::InstructionFactory as InstructionFactory;
 use super::instruction::Instruction as Instruction;
+use super::location::Location as Location;
+use super::device::{Device, CpuState};
+use super::error::CpuError as CpuError;
+use super::register::Register as Register;
+use super::debug::{DebugInfo, SourceLocation};
 
+/// nominal clock speed of the DCPU-16 in cycles per second
+pub const CLOCK_HZ: u64 = 100_000;
+
+/// number of interrupts that can wait in the queue before the cpu catches fire
+pub const MAX_QUEUED_INTERRUPTS: usize = 256;
+
+/// what happened during a single step of the cpu
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct StepOutcome {
+    pub cycles: u64,                // cycles the step took
+    pub interrupt: Option<u16>      // message of the interrupt triggered before the instruction
+}
+
+/// why the cpu stopped running
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum StopReason {
+    /// pc left the program loaded into memory
+    EndOfProgram,
+    /// requested number of cycles has elapsed
+    Cycles,
+    /// requested number of instructions has been executed
+    Instructions,
+    /// predicate passed to run_until returned true
+    Condition,
+    /// the cpu is stuck in a loop jumping to itself, e.g. SUB PC, 1
+    Halted { pc: u16 }
+}
+
+#[derive(Default)]
 pub struct Cpu {
     memory: Memory,
     registers: [u16; 8], // A - J
-    cyc: u16,     // cycle
+    cycles: u64,  // cycles elapsed since the cpu was created
     pc: u16,      // program_counter
     sp: u16,      // stack_pointer
     ex: u16,      // extra
-    ia: u16       // interupt address
-}
-
-impl Default for Cpu {
-    fn default() -> Cpu {
-        Cpu {
-            memory: Default::default(),
-            registers: [0; 8],
-            cyc: 0,
-            pc: 0,
-            sp: 0,
-            ex: 0,
-            ia: 0
-        }
-    }
+    ia: u16,      // interupt address
+    interrupts: VecDeque<u16>,  // queued interrupt messages
+    queueing: bool,             // interrupts are added to the queue instead of triggered
+    on_fire: bool,              // the interrupt queue overflowed
+    devices: Vec<Box<dyn Device>>,
+    debug_info: Option<DebugInfo>   // maps addresses of the loaded program to its sources
 }
 
 impl Cpu {
@@ -41,16 +69,125 @@ impl Cpu {
     pub fn i(&self) -> u16 { self.registers[6] }
     pub fn j(&self) -> u16 { self.registers[7] }
 
+    pub fn get_reg(&self, reg: Register) -> u16 {
+        match reg {
+            Register::A => self.registers[0],
+            Register::B => self.registers[1],
+            Register::C => self.registers[2],
+            Register::X => self.registers[3],
+            Register::Y => self.registers[4],
+            Register::Z => self.registers[5],
+            Register::I => self.registers[6],
+            Register::J => self.registers[7],
+            Register::PC => self.pc,
+            Register::SP => self.sp,
+            Register::EX => self.ex,
+            Register::IA => self.ia
+        }
+    }
+
+    pub fn set_reg(&mut self, reg: Register, value: u16) {
+        match reg {
+            Register::A => self.registers[0] = value,
+            Register::B => self.registers[1] = value,
+            Register::C => self.registers[2] = value,
+            Register::X => self.registers[3] = value,
+            Register::Y => self.registers[4] = value,
+            Register::Z => self.registers[5] = value,
+            Register::I => self.registers[6] = value,
+            Register::J => self.registers[7] = value,
+            Register::PC => self.pc = value,
+            Register::SP => self.sp = value,
+            Register::EX => self.ex = value,
+            Register::IA => self.ia = value
+        }
+    }
+
+    pub fn read_memory(&self, address: u16) -> u16 {
+        self.memory.get(address as usize)
+    }
+
+    pub fn write_memory(&mut self, address: u16, word: u16) {
+        self.memory.set(address as usize, word);
+    }
+
+    pub fn cycles(&self) -> u64 { self.cycles }
+
+    pub fn ia(&self) -> u16 { self.ia }
+    pub fn is_queueing(&self) -> bool { self.queueing }
+    pub fn queued_interrupts(&self) -> usize { self.interrupts.len() }
+    pub fn is_on_fire(&self) -> bool { self.on_fire }
+
+    /// connects a device to the cpu, devices are numbered in the order they are connected
+    pub fn connect(&mut self, device: Box<dyn Device>) {
+        self.devices.push(device);
+    }
+
+    pub fn device_count(&self) -> usize { self.devices.len() }
+
     pub fn load_program(&mut self, words: &[u16]) {
         self.memory.load(words);
     }
 
+    pub fn load_program_at(&mut self, address: u16, words: &[u16]) {
+        self.memory.load_at(address, words);
+    }
+
+    /// loads a raw binary image, returns the number of loaded words
+    pub fn load_binary<R: Read>(&mut self, address: u16, reader: R, endianness: Endianness) -> io::Result<usize> {
+        self.memory.load_binary(address, reader, endianness)
+    }
+
+    /// attaches the debug info of the loaded program
+    pub fn set_debug_info(&mut self, info: DebugInfo) {
+        self.debug_info = Some(info);
+    }
+
+    /// reads debug info written by `DebugInfo::write`, e.g. next to a binary
+    pub fn load_debug_info<R: BufRead>(&mut self, reader: R) -> io::Result<()> {
+        self.debug_info = Some(DebugInfo::read(reader)?);
+        Ok(())
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> { self.debug_info.as_ref() }
+
+    /// source of the instruction at pc, if debug info is loaded
+    pub fn source_location(&self) -> Option<&SourceLocation> {
+        self.debug_info.as_ref().and_then(|info| info.location(self.pc))
+    }
+
+    /// address in terms of the sources if debug info is loaded, e.g. for traces
+    pub fn describe_address(&self, address: u16) -> String {
+        match self.debug_info {
+            Some(ref info) => info.describe(address),
+            None => format!("{:#06x}", address)
+        }
+    }
+
+    /// the error's message followed by where in the sources it happened, if debug info is loaded
+    pub fn describe_error(&self, err: &CpuError) -> String {
+        match (err.pc(), self.debug_info.as_ref()) {
+            (Some(pc), Some(info)) => format!("{} in {}", err, info.describe(pc)),
+            _ => err.to_string()
+        }
+    }
+
+    pub fn clear_memory(&mut self) {
+        self.memory.clear();
+    }
+
     fn read_word(&mut self) -> u16 {
         let res = self.memory.get(self.pc as usize);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         res
     }
 
+    /// reads operand's next word, which takes one extra cycle
+    fn read_next_word(&mut self) -> u16 {
+        self.cycles += 1;
+        self.read_word()
+    }
+
     fn push(&mut self, word: u16) {
         self.sp = self.sp.wrapping_sub(1);    
         self.memory.set(self.sp as usize, word);
@@ -62,294 +199,537 @@ impl Cpu {
         res
     }
 
-    pub fn run(&mut self) {
-        while self.memory.has_word_at(self.pc as usize) {
-            self.run_step();
+    /// sends an interrupt with `message` to the cpu, e.g. from hardware.
+    /// interrupts are queued and triggered one at a time between instructions
+    pub fn interrupt(&mut self, message: u16) {
+        if self.interrupts.len() >= MAX_QUEUED_INTERRUPTS {
+            self.on_fire = true;
+            return;
+        }
+        self.interrupts.push_back(message);
+    }
+
+    /// triggers an interrupt, unless interrupts are being queued.
+    /// does nothing if IA is 0
+    fn trigger_interrupt(&mut self, message: u16) {
+        if self.queueing {
+            self.interrupt(message);
+            return;
+        }
+        if self.ia != 0 {
+            self.queueing = true;
+            let pc = self.pc;
+            self.push(pc);
+            let reg_a = self.registers[0];
+            self.push(reg_a);
+            self.pc = self.ia;
+            self.registers[0] = message;
+        }
+    }
+
+    /// triggers at most one interrupt from the queue, returns its message
+    fn handle_interrupt(&mut self) -> Option<u16> {
+        if self.queueing {
+            return None;
+        }
+        let message = self.interrupts.pop_front();
+        if let Some(message) = message {
+            self.trigger_interrupt(message);
+        }
+        message
+    }
+
+    /// checks if a loop jumping to itself can never be left
+    fn is_stuck(&self) -> bool {
+        self.ia == 0 || self.queueing || (self.interrupts.is_empty() && self.devices.is_empty())
+    }
+
+    /// runs the loaded program until pc leaves it or the cpu halts
+    pub fn run(&mut self) -> Result<StopReason, CpuError> {
+        self.run_while(|cpu| match cpu.memory.has_word_at(cpu.pc as usize) {
+            true => None,
+            false => Some(StopReason::EndOfProgram)
+        })
+    }
+
+    /// runs instructions until at least `cycles` cycles have elapsed
+    pub fn run_for_cycles(&mut self, cycles: u64) -> Result<StopReason, CpuError> {
+        let end = self.cycles + cycles;
+        self.run_while(|cpu| match cpu.cycles >= end {
+            true => Some(StopReason::Cycles),
+            false => None
+        })
+    }
+
+    /// runs as many cycles as the cpu would execute in `duration` at `CLOCK_HZ`
+    pub fn run_for_duration(&mut self, duration: Duration) -> Result<StopReason, CpuError> {
+        let cycles = duration.as_secs() * CLOCK_HZ + (duration.subsec_nanos() as u64 * CLOCK_HZ) / 1_000_000_000;
+        self.run_for_cycles(cycles)
+    }
+
+    pub fn run_for_instructions(&mut self, instructions: u64) -> Result<StopReason, CpuError> {
+        let mut executed = 0;
+        self.run_while(|_| match executed >= instructions {
+            true => Some(StopReason::Instructions),
+            false => {
+                executed += 1;
+                None
+            }
+        })
+    }
+
+    /// runs instructions until `predicate` returns true, it is checked before every step
+    pub fn run_until<F>(&mut self, mut predicate: F) -> Result<StopReason, CpuError> where F: FnMut(&Cpu) -> bool {
+        self.run_while(|cpu| match predicate(cpu) {
+            true => Some(StopReason::Condition),
+            false => None
+        })
+    }
+
+    /// runs instructions until `stop` returns a reason or the cpu halts
+    fn run_while<F>(&mut self, mut stop: F) -> Result<StopReason, CpuError> where F: FnMut(&Cpu) -> Option<StopReason> {
+        loop {
+            if let Some(reason) = stop(self) {
+                return Ok(reason);
+            }
+            match self.run_step() {
+                Ok(_) => {},
+                Err(CpuError::Halted { pc }) => return Ok(StopReason::Halted { pc }),
+                Err(err) => return Err(err)
+            }
         }
     }
 
-    pub fn run_step(&mut self) {
+    /// executes a single instruction, preceded by at most one interrupt
+    pub fn run_step(&mut self) -> Result<StepOutcome, CpuError> {
+        if self.on_fire {
+            return Err(CpuError::OnFire);
+        }
+        let interrupt = self.handle_interrupt();
+        let start = self.cycles;
+        let pc = self.pc;
         let word = self.read_word();
-        match InstructionFactory::new(&word) {
+        let instruction = InstructionFactory::new(&word);
+        self.cycles += instruction.cycles();
+        let only_writes_pc = instruction.only_writes_pc();
+        match instruction {
             Instruction::SET(a, b) => {
-                let va = self.get_value(a);
-                self.set_value(b, va);
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                self.write(lb, va);
             },
             Instruction::ADD(a, b) => {
-                let va = self.get_value(a) as u32;
-                let vb = self.get_value(b) as u32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as u32;
+                let vb = self.read(lb) as u32;
                 let res = vb + va;
-                self.set_value(b, res as u16);
+                self.write(lb, res as u16);
                 self.ex = match res > 0xffff {
                     true => 0x1,
                     false => 0x0
                 };
             },
             Instruction::SUB(a, b) => {
-                let va = self.get_value(a) as i32;
-                let vb = self.get_value(b) as i32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as i32;
+                let vb = self.read(lb) as i32;
                 let res = vb - va;
-                self.set_value(b, res as u16);
+                self.write(lb, res as u16);
                 self.ex = match res < 0 {
                     true => 0xffff,
                     false => 0x0
                 };
             },
             Instruction::MUL(a, b) => {
-                let va = self.get_value(a) as u32;
-                let vb = self.get_value(b) as u32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as u32;
+                let vb = self.read(lb) as u32;
                 let res = vb * va;
-                self.set_value(b, res as u16);
+                self.write(lb, res as u16);
                 self.ex = ((res >> 16) & 0xffff) as u16;
             },
             Instruction::MLI(a, b) => {
-                let va = self.get_value(a) as i16 as i32;
-                let vb = self.get_value(b) as i16 as i32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as i16 as i32;
+                let vb = self.read(lb) as i16 as i32;
                 let res = vb * va;
-                self.set_value(b, res as u16);
+                self.write(lb, res as u16);
                 self.ex = ((res >> 16) & 0xffff) as u16;
             }, 
             Instruction::DIV(a, b) => {
-                let va = self.get_value(a) as u32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as u32;
                 match va {
                     0 => {
-                        self.set_value(b, 0);
+                        self.write(lb, 0);
                         self.ex = 0;
                     },
                     _ => {
-                        let vb = self.get_value(b) as u32;
+                        let vb = self.read(lb) as u32;
                         let res = vb / va;
-                        self.set_value(b, res as u16);
+                        self.write(lb, res as u16);
                         self.ex = (((vb << 16) / va) & 0xffff) as u16;
                     }
                 };
             },
             Instruction::DVI(a, b) => {
-                let va = self.get_value(a) as i16 as i32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as i16 as i32;
                 match va {
                     0 => {
-                        self.set_value(b, 0);
+                        self.write(lb, 0);
                         self.ex = 0;
                     },
                     _ => {
-                        let vb = self.get_value(b) as i16 as i32;
+                        // wide enough for -0x8000 / -1
+                        let va = va as i64;
+                        let vb = self.read(lb) as i16 as i64;
                         let res = vb / va;
-                        println!("a: {}, b: {}, res: {}", va, vb, res);
-                        self.set_value(b, res as u16);
+                        self.write(lb, res as u16);
                         self.ex = (((vb << 16) / va) & 0xffff) as u16;
                     }
                 };
             },
             Instruction::MOD(a, b) => {
-                let va = self.get_value(a);
-                let vb = self.get_value(b);
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                let vb = self.read(lb);
                 match va {
-                    0 => self.set_value(b, 0),
-                    _ => self.set_value(b, vb % va)
+                    0 => self.write(lb, 0),
+                    _ => self.write(lb, vb % va)
                 };
             },
             Instruction::MDI(a, b) => {
-                let va = self.get_value(a) as i16;
-                let vb = self.get_value(b) as i16;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as i16;
+                let vb = self.read(lb) as i16;
                 match va {
-                    0 => self.set_value(b, 0),
-                    _ => self.set_value(b, (vb % va) as u16)
+                    0 => self.write(lb, 0),
+                    _ => self.write(lb, vb.wrapping_rem(va) as u16)
                 };
             },
             Instruction::AND(a, b) => {
-                let va = self.get_value(a);
-                let vb = self.get_value(b);
-                self.set_value(b, vb & va); 
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                let vb = self.read(lb);
+                self.write(lb, vb & va); 
             },
             Instruction::BOR(a, b) => {
-                let va = self.get_value(a);
-                let vb = self.get_value(b);
-                self.set_value(b, vb | va); 
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                let vb = self.read(lb);
+                self.write(lb, vb | va); 
             },
             Instruction::XOR(a, b) => {
-                let va = self.get_value(a);
-                let vb = self.get_value(b);
-                self.set_value(b, vb ^ va); 
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                let vb = self.read(lb);
+                self.write(lb, vb ^ va); 
             },
+            // shift counts above 32 are clamped, by then every bit of b and ex is shifted out
             Instruction::SHR(a, b) => {
-                let va = self.get_value(a) as u32;
-                let vb = self.get_value(b) as u32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la).min(32) as u32;
+                let vb = self.read(lb) as u64;
                 let res = vb >> va;
-                self.set_value(b, res as u16); 
+                self.write(lb, res as u16); 
                 self.ex = (((vb << 16) >> va) & 0xffff) as u16;
             },
             Instruction::ASR(a, b) => {
-                let va = self.get_value(a) as i16 as i32;
-                let vb = self.get_value(b) as i16 as i32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la).min(32) as u32;
+                let vb = self.read(lb) as i16 as i64;
                 let res = vb >> va;
-                self.set_value(b, res as u16); 
+                self.write(lb, res as u16); 
                 self.ex = (((vb << 16) >> va) & 0xffff) as u16;
             },
             Instruction::SHL(a, b) => {
-                let va = self.get_value(a) as u32;
-                let vb = self.get_value(b) as u32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la).min(32) as u32;
+                let vb = self.read(lb) as u64;
                 let res = vb << va;
-                self.set_value(b, res as u16); 
+                self.write(lb, res as u16); 
                 self.ex = (((vb << va) >> 16) & 0xffff) as u16;
             },
             Instruction::IFB(a, b) => {
-                let va = self.get_value(a);
-                let vb = self.get_value(b);
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                let vb = self.read(lb);
                 if (vb & va) == 0 {
-                    self.pc = self.pc + 1;
+                    self.skip();
                 }
             },
             Instruction::IFC(a, b) => {
-                let va = self.get_value(a);
-                let vb = self.get_value(b);
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                let vb = self.read(lb);
                 if (vb & va) != 0 {
-                    self.pc = self.pc + 1;
+                    self.skip();
                 }
             },
             Instruction::IFE(a, b) => {
-                let va = self.get_value(a);
-                let vb = self.get_value(b);
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                let vb = self.read(lb);
                 if vb != va {
-                    self.pc = self.pc + 1;
+                    self.skip();
                 }
             },
             Instruction::IFN(a, b) => {
-                let va = self.get_value(a);
-                let vb = self.get_value(b);
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                let vb = self.read(lb);
                 if vb == va {
-                    self.pc = self.pc + 1;
+                    self.skip();
                 }
             },
             Instruction::IFG(a, b) => {
-                let va = self.get_value(a);
-                let vb = self.get_value(b);
-                if !(vb > va) {
-                    self.pc = self.pc + 1;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                let vb = self.read(lb);
+                if vb <= va {
+                    self.skip();
                 }
             },
             Instruction::IFA(a, b) => {
-                let va = self.get_value(a) as i16;
-                let vb = self.get_value(b) as i16;
-                if !(vb > va) {
-                    self.pc = self.pc + 1;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as i16;
+                let vb = self.read(lb) as i16;
+                if vb <= va {
+                    self.skip();
                 }
             },
             Instruction::IFL(a, b) => {
-                let va = self.get_value(a);
-                let vb = self.get_value(b);
-                if !(vb < va) {
-                    self.pc = self.pc + 1;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                let vb = self.read(lb);
+                if vb >= va {
+                    self.skip();
                 }
             },
             Instruction::IFU(a, b) => {
-                let va = self.get_value(a) as i16;
-                let vb = self.get_value(b) as i16;
-                if !(vb < va) {
-                    self.pc = self.pc + 1;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as i16;
+                let vb = self.read(lb) as i16;
+                if vb >= va {
+                    self.skip();
                 }
             },
             Instruction::ADX(a, b) => {
-                let va = self.get_value(a) as u32;
-                let vb = self.get_value(b) as u32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as u32;
+                let vb = self.read(lb) as u32;
                 let ex = self.ex as u32;
                 let res = vb + va + ex;
-                self.set_value(b, res as u16);
+                self.write(lb, res as u16);
                 self.ex = match res > 0xffff {
                     true => 0x1,
                     false => 0x0
                 };
             },
             Instruction::SBX(a, b) => {
-                let va = self.get_value(a) as i32;
-                let vb = self.get_value(b) as i32;
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la) as i32;
+                let vb = self.read(lb) as i32;
                 let ex = self.ex as i32;
                 let res = vb - va + ex;
-                self.set_value(b, res as u16);
+                self.write(lb, res as u16);
                 self.ex = match res < 0 {
                     true => 0xffff,
                     false => 0x0
                 };
             },
             Instruction::STI(a, b) => {
-                let va = self.get_value(a);
-                self.set_value(b, va);
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                self.write(lb, va);
                 self.registers[6] = self.registers[6].wrapping_add(1);
                 self.registers[7] = self.registers[7].wrapping_add(1);
             },
             Instruction::STD(a, b) => {
-                let va = self.get_value(a);
-                self.set_value(b, va);
+                let (la, lb) = self.resolve_operands(a, b);
+                let va = self.read(la);
+                self.write(lb, va);
                 self.registers[6] = self.registers[6].wrapping_sub(1);
                 self.registers[7] = self.registers[7].wrapping_sub(1);
             },
             Instruction::JSR(a) => {
-                let va = self.get_value(a);
-                let address = self.pc + 1;
+                let la = self.resolve_a(a);
+                let va = self.read(la);
+                let address = self.pc;
                 self.push(address);
                 self.pc = va;
             },
-            Instruction::INT(a) if self.ia != 0 => {
-                let va = self.get_value(a);
-                let pc = self.pc;
-                self.push(pc);
-                let reg_a = self.registers[0];
-                self.push(reg_a);
-                self.pc = self.ia;
-                self.registers[0] = va;
+            Instruction::INT(a) => {
+                let la = self.resolve_a(a);
+                let va = self.read(la);
+                self.trigger_interrupt(va);
             },
-            Instruction::INT(_) => {},
             Instruction::IAG(a) => {
+                let la = self.resolve_a(a);
                 let ia = self.ia;
-                self.set_value(a, ia);
+                self.write(la, ia);
             },
             Instruction::IAS(a) => {
-                let va = self.get_value(a);
+                let la = self.resolve_a(a);
+                let va = self.read(la);
                 self.ia = va;
             },
             Instruction::RFI(a) => {
-                let _va = self.get_value(a);
+                self.resolve_a(a);
+                self.queueing = false;
                 self.registers[0] = self.pop();
                 self.pc = self.pop();
+            },
+            Instruction::IAQ(a) => {
+                let la = self.resolve_a(a);
+                let va = self.read(la);
+                self.queueing = va != 0;
+            },
+            Instruction::HWN(a) => {
+                let la = self.resolve_a(a);
+                let count = self.devices.len() as u16;
+                self.write(la, count);
+            },
+            Instruction::HWQ(a) => {
+                let la = self.resolve_a(a);
+                let va = self.read(la) as usize;
+                let (id, version, manufacturer) = match self.devices.get(va) {
+                    Some(device) => (device.id(), device.version(), device.manufacturer()),
+                    None => (0, 0, 0)
+                };
+                self.registers[0] = id as u16;
+                self.registers[1] = (id >> 16) as u16;
+                self.registers[2] = version;
+                self.registers[3] = manufacturer as u16;
+                self.registers[4] = (manufacturer >> 16) as u16;
+            },
+            Instruction::HWI(a) => {
+                let la = self.resolve_a(a);
+                let va = self.read(la) as usize;
+                if let Some(device) = self.devices.get_mut(va) {
+                    let mut state = CpuState {
+                        registers: &mut self.registers,
+                        memory: &mut self.memory,
+                        pc: &mut self.pc,
+                        sp: &mut self.sp,
+                        ex: &mut self.ex,
+                        ia: &mut self.ia
+                    };
+                    self.cycles += device.interrupt(&mut state);
+                }
+            },
+            Instruction::NULL => {
+                self.pc = pc;
+                return Err(CpuError::IllegalInstruction { pc, word });
+            }
+        }
+        let elapsed = self.cycles - start;
+        self.tick_devices(elapsed);
+        if self.on_fire {
+            return Err(CpuError::OnFire);
+        }
+        if self.pc == pc && only_writes_pc && self.is_stuck() {
+            return Err(CpuError::Halted { pc });
+        }
+        Ok(StepOutcome { cycles: elapsed, interrupt })
+    }
+
+    /// lets devices know how many cycles have passed and queues interrupts they send
+    fn tick_devices(&mut self, cycles: u64) {
+        let messages: Vec<u16> = self.devices.iter_mut()
+            .filter_map(|device| device.tick(cycles))
+            .collect();
+        for message in messages {
+            self.interrupt(message);
+        }
+    }
+
+    /// skips the next instruction, together with its next words, at the cost
+    /// of one cycle. skipped IF instructions make the cpu skip one more
+    fn skip(&mut self) {
+        loop {
+            let word = self.memory.get(self.pc as usize);
+            self.pc = self.pc.wrapping_add(InstructionFactory::length(&word));
+            self.cycles += 1;
+            if !InstructionFactory::new(&word).is_conditional() {
+                break;
+            }
+        }
+    }
+
+    /// resolves both operands of a basic instruction, a is handled before b
+    fn resolve_operands(&mut self, a: u8, b: u8) -> (Location, Location) {
+        let la = self.resolve_a(a);
+        let lb = self.resolve_b(b);
+        (la, lb)
+    }
+
+    fn resolve_a(&mut self, a: u8) -> Location {
+        match a {
+            0x18 => {                                   // pop
+                let sp = self.sp;
+                self.sp = sp.wrapping_add(1);
+                Location::Memory(sp)
+            },
+            n @ 0x20 ..= 0x3f => Location::Literal((n as u16).wrapping_sub(0x21)),
+            n => self.resolve(n)
+        }
+    }
 
+    fn resolve_b(&mut self, b: u8) -> Location {
+        match b {
+            0x18 => {                                   // push
+                self.sp = self.sp.wrapping_sub(1);
+                Location::Memory(self.sp)
             },
-            _ => panic!()
+            n => self.resolve(n)
         }
     }
 
-    fn get_value(&mut self, addr: u8) -> u16 {
-        match addr {
-            n @ 0x0 ... 0x7 => self.registers[n as usize],
-            n @ 0x8 ... 0xf => self.memory.get(self.registers[(n - 0x8) as usize] as usize),
-            n @ 0x10 ... 0x17 => {
-                let v = self.memory.get(self.registers[(n - 0x10) as usize] as usize);
-                let word = self.read_word();
-                self.memory.get(v.wrapping_add(word) as usize)
+    fn resolve(&mut self, value: u8) -> Location {
+        match value {
+            n @ 0x0 ..= 0x7 => Location::Register(n as usize),
+            n @ 0x8 ..= 0xf => Location::Memory(self.registers[(n - 0x8) as usize]),
+            n @ 0x10 ..= 0x17 => {
+                let v = self.registers[(n - 0x10) as usize];
+                let word = self.read_next_word();
+                Location::Memory(v.wrapping_add(word))
             },
-            0x18 => self.pop(),
-            0x19 => self.memory.get(self.sp as usize), // peek
+            0x19 => Location::Memory(self.sp),          // peek
             0x1a => {
                 let sp = self.sp;
-                let word = self.read_word();
-                self.memory.get(sp.wrapping_add(word) as usize)
-            },
-            0x1b => self.sp,
-            0x1c => self.pc,
-            0x1d => self.ex,
-            0x1e => {                                   // TODO: test
-                let word = self.read_word() as usize;
-                self.memory.get(word)
-            },
-            0x1f => self.read_word(),
-            n @ 0x20 ... 0x3f => ((n as u16).wrapping_sub(0x21)),
-            _ => panic!()
+                let word = self.read_next_word();
+                Location::Memory(sp.wrapping_add(word))
+            },
+            0x1b => Location::Sp,
+            0x1c => Location::Pc,
+            0x1d => Location::Ex,
+            0x1e => Location::Memory(self.read_next_word()),
+            0x1f => Location::Literal(self.read_next_word()),
+            _ => unreachable!()                         // push, pop and short literals are resolved by the caller
         }
     }
 
-    fn set_value(&mut self, addr: u8, value: u16) {
-        match addr {
-            n @ 0x0 ... 0x7 => self.registers[n as usize] = value,
-            0x18 => self.push(value),
-            _ => panic!()
+    fn read(&self, location: Location) -> u16 {
+        match location {
+            Location::Register(n) => self.registers[n],
+            Location::Memory(address) => self.memory.get(address as usize),
+            Location::Sp => self.sp,
+            Location::Pc => self.pc,
+            Location::Ex => self.ex,
+            Location::Literal(v) => v
+        }
+    }
+
+    fn write(&mut self, location: Location, value: u16) {
+        match location {
+            Location::Register(n) => self.registers[n] = value,
+            Location::Memory(address) => self.memory.set(address as usize, value),
+            Location::Sp => self.sp = value,
+            Location::Pc => self.pc = value,
+            Location::Ex => self.ex = value,
+            Location::Literal(_) => {}
         }
     }
 }
@@ -361,7 +741,7 @@ fn test_set() {
              0xfc01,        // SET A, 30
              0x7c21, 0x001f // SET B, 31
     ]);
-    cpu.run();
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 30);
     assert_eq!(cpu.registers[1], 31);
     assert_eq!(cpu.pc, 3);
@@ -375,7 +755,7 @@ fn test_add() {
              0x8821,    // SET B, 1
              0x0022     // ADD B, A
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 30);
     assert_eq!(cpu.registers[1], 31);
     assert_eq!(cpu.pc, 3);
@@ -389,7 +769,7 @@ fn test_add_overflow() {
              0x8001,    // SET A, 0xffff
              0x8c02     // ADD A, 2
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 1);
     assert_eq!(cpu.pc, 2);
     assert_eq!(cpu.ex, 1);
@@ -402,7 +782,7 @@ fn test_sub() {
              0x7c01, 0x0022,    // SET A, 34
              0x7c03, 0x001f     // SUB A, 31
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 3);
     assert_eq!(cpu.pc, 4);
 }
@@ -414,7 +794,7 @@ fn test_sub_underflow() {
              0x8c01,    // SET A, 2
              0x9403     // SUB A, 4
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 0xfffe);
     assert_eq!(cpu.pc, 2);
     assert_eq!(cpu.ex, 0xffff);
@@ -431,7 +811,7 @@ fn test_push_pop_peek() {
              0x6002,            // ADD A, POP
              0x6421             // SET B, PEEK
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 36);
     assert_eq!(cpu.registers[1], 2);
     assert_eq!(cpu.pc, 7);
@@ -446,7 +826,7 @@ fn test_registers() {
              0x8001,    // SET A, 0xffff
              0x2021     // SET B, [A]
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
 
     assert_eq!(cpu.memory.get(0xffff), 10);
     assert_eq!(cpu.registers[0], 0xffff);
@@ -464,7 +844,7 @@ fn test_jsr() {
              0x0422,    // ADD B, B
              0x0420     // JSR B
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 1);
     assert_eq!(cpu.registers[1], 4);
     assert_eq!(cpu.sp, 0xfffe);
@@ -478,7 +858,7 @@ fn test_mul() {
              0xc001,    // SET A, 15
              0x8c04     // MUL A, 2
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 30);
     assert_eq!(cpu.pc, 2);
 }
@@ -490,7 +870,7 @@ fn test_mul_overflow() {
              0x9401,    // SET A, 4
              0x8004     // MUL A, 0xffff
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 0xfffc);
     assert_eq!(cpu.pc, 2);
     assert_eq!(cpu.ex, 3);
@@ -503,7 +883,7 @@ fn test_mli() {
              0xc001,    // SET A, 15
              0x8c05     // MLI A, 2
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 30);
     assert_eq!(cpu.pc, 2);
 }
@@ -515,7 +895,7 @@ fn test_mli_overflow() {
              0x9401,    // SET A, 4
              0x8005     // MLI A, 0xffff
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 0xfffc);
     assert_eq!(cpu.pc, 2);
     assert_eq!(cpu.ex, 0xffff); 
@@ -528,7 +908,7 @@ fn test_div() {
              0x9801,    // SET A, 5
              0x8c06     // DIV A, 2
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 2);
     assert_eq!(cpu.pc, 2);
     assert_eq!(cpu.ex, 0x8000);
@@ -541,7 +921,7 @@ fn test_dvi() {
              0x9801,    // SET A, 5
              0x8c07     // DVI A, 2
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 2);
     assert_eq!(cpu.pc, 2);
 }
@@ -553,12 +933,25 @@ fn test_dvi_signed() {
              0x9801,            // SET A, 5
              0x7c07, 0xfffe     // DVI A, -2
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 0xfffe);
     assert_eq!(cpu.pc, 3);
     assert_eq!(cpu.ex, 0x8000);
 }
 
+#[test]
+fn test_dvi_overflow() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x7c01, 0x8000,    // SET A, 0x8000
+             0x8007             // DVI A, -1
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[0], 0x8000);
+    assert_eq!(cpu.pc, 3);
+    assert_eq!(cpu.ex, 0);
+}
+
 #[test]
 fn test_mod() {
     let mut cpu: Cpu = Default::default();
@@ -566,7 +959,7 @@ fn test_mod() {
              0x9801,    // SET A, 5
              0x8c08     // MOD A, 2
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 1);
     assert_eq!(cpu.pc, 2);
 }
@@ -578,7 +971,7 @@ fn test_mdi() {
              0x9801,    // SET A, 5
              0x8c09     // MDI A, 2
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 1);
     assert_eq!(cpu.pc, 2);
 }
@@ -590,7 +983,7 @@ fn test_mdi_signed() {
              0x7c01, 0xfff9,    // SET A, -7
              0xc409             // MDI A, 16
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 0xfff9);
     assert_eq!(cpu.pc, 3);
 }
@@ -602,7 +995,7 @@ fn test_and() {
              0xa001,    // SET A, 7
              0x980a     // AND A, 5
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 5);
     assert_eq!(cpu.pc, 2);
 }
@@ -614,7 +1007,7 @@ fn test_bor() {
              0x9001,    // SET A, 3
              0x980b     // BOR A, 5
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 7);
     assert_eq!(cpu.pc, 2);
 }
@@ -626,7 +1019,7 @@ fn test_xor() {
              0x9001,    // SET A, 3
              0x980c     // XOR A, 5
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 6);
     assert_eq!(cpu.pc, 2);
 }
@@ -638,7 +1031,7 @@ fn test_shr() {
              0xa001,    // SET A, 7
              0x880d     // SHR A, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 3);
     assert_eq!(cpu.pc, 2);
     assert_eq!(cpu.ex, 0x8000);
@@ -651,7 +1044,7 @@ fn test_asr() {
              0xa001,    // SET A, 7
              0x880e     // ASR A, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 3);
     assert_eq!(cpu.pc, 2);
     assert_eq!(cpu.ex, 0x8000);
@@ -664,7 +1057,7 @@ fn test_shl() {
              0xa001,    // SET A, 7
              0x880f     // SHL A, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 14);
     assert_eq!(cpu.pc, 2);
 }
@@ -676,12 +1069,39 @@ fn test_shl_ex() {
              0xa001,    // SET A, 7
              0xe40f     // SHL A, 24
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 0);
     assert_eq!(cpu.pc, 2);
     assert_eq!(cpu.ex, 0x0700);
 }
 
+#[test]
+fn test_long_shifts() {
+    // (instruction, b, shift, result, ex)
+    let cases = [
+        (0x0f, 0x0007, 16, 0x0000, 0x0007),     // SHL
+        (0x0f, 0x0007, 31, 0x0000, 0x8000),
+        (0x0f, 0x0001, 40, 0x0000, 0x0000),
+        (0x0d, 0x8000, 16, 0x0000, 0x8000),     // SHR
+        (0x0d, 0x8000, 31, 0x0000, 0x0001),
+        (0x0d, 0x8000, 40, 0x0000, 0x0000),
+        (0x0e, 0x8000, 16, 0xffff, 0x8000),     // ASR
+        (0x0e, 0x8000, 31, 0xffff, 0xffff),
+        (0x0e, 0x8000, 40, 0xffff, 0xffff),
+        (0x0e, 0x4000, 40, 0x0000, 0x0000),
+        (0x0e, 0x8000, 0xffff, 0xffff, 0xffff)
+    ];
+    for &(op, b, shift, result, ex) in cases.iter() {
+        let mut cpu: Cpu = Default::default();
+        cpu.load_program(&[
+                 0x7c01, b,             // SET A, b
+                 0x7c00 | op, shift     // op A, shift
+        ]);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[0], cpu.ex), (result, ex), "{:#x} {:#x} {}", op, b, shift);
+    }
+}
+
 #[test]
 fn test_ifb() {
     let mut cpu: Cpu = Default::default();
@@ -692,7 +1112,7 @@ fn test_ifb() {
              0x8810,    // IFB A, 1
              0x8821     // SET B, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 2);
     assert_eq!(cpu.registers[1], 0);
     assert_eq!(cpu.pc, 5);
@@ -708,7 +1128,7 @@ fn test_ifc() {
              0x8c11,    // IFC A, 2
              0x8821     // SET B, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 1);
     assert_eq!(cpu.registers[1], 1);
     assert_eq!(cpu.pc, 5);
@@ -724,7 +1144,7 @@ fn test_ife() {
              0x8812,    // IFE A, 1
              0x8821     // SET B, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 1);
     assert_eq!(cpu.registers[1], 1);
     assert_eq!(cpu.pc, 5);
@@ -740,7 +1160,7 @@ fn test_ifn() {
              0x8c13,    // IFN A, 2
              0x8821     // SET B, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 2);
     assert_eq!(cpu.registers[1], 0);
     assert_eq!(cpu.pc, 5);
@@ -756,7 +1176,7 @@ fn test_ifg() {
              0x8814,    // IFG A, 1
              0x8821     // SET B, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 1);
     assert_eq!(cpu.registers[1], 0);
     assert_eq!(cpu.pc, 5);
@@ -772,7 +1192,7 @@ fn test_ifa() {
              0x8815,    // IFA A, 1
              0x8821     // SET B, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 1);
     assert_eq!(cpu.registers[1], 0);
     assert_eq!(cpu.pc, 5);
@@ -788,7 +1208,7 @@ fn test_ifl() {
              0x8c16,    // IFL A, 2
              0x8821     // SET B, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 2);
     assert_eq!(cpu.registers[1], 0);
     assert_eq!(cpu.pc, 5);
@@ -804,7 +1224,7 @@ fn test_ifu() {
              0x8c17,    // IFU A, 2
              0x8821     // SET B, 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 2);
     assert_eq!(cpu.registers[1], 0);
     assert_eq!(cpu.pc, 5);
@@ -816,7 +1236,7 @@ fn test_sti() {
     cpu.load_program(&[
              0xc01e     // STI A, 15
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 15);
     assert_eq!(cpu.registers[6], 1);
     assert_eq!(cpu.registers[7], 1);
@@ -829,7 +1249,7 @@ fn test_std() {
     cpu.load_program(&[
              0xc01f     // STD A, 15
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 15);
     assert_eq!(cpu.registers[6], 0xffff);
     assert_eq!(cpu.registers[7], 0xffff);
@@ -846,7 +1266,7 @@ fn test_int() {
              0x9021,            // SET B, 3
              0xa041             // SET C, 7
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 8);
     assert_eq!(cpu.registers[1], 0);
     assert_eq!(cpu.registers[2], 7);
@@ -866,7 +1286,7 @@ fn test_rfi() {
              0xa042,            // ADD C, 7
              0x7d60, 0x0001     // RFI 1
     ]);
-    cpu.run(); 
+    cpu.run().unwrap();
     assert_eq!(cpu.registers[0], 0x9401);
     assert_eq!(cpu.registers[1], 3);
     assert_eq!(cpu.registers[2], 14);
@@ -875,3 +1295,628 @@ fn test_rfi() {
     assert_eq!(cpu.ia, 6);
 }
 
+#[test]
+fn test_set_register_indirect() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x7c01, 0x1000,    // SET A, 0x1000
+             0x9901             // SET [A], 5
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(0x1000), 5);
+    assert_eq!(cpu.pc, 3);
+}
+
+#[test]
+fn test_set_register_next_word() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x7c21, 0x1000,    // SET B, 0x1000
+             0x9a21, 0x0004,    // SET [B+4], 5
+             0x4401, 0x0004     // SET A, [B+4]
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(0x1004), 5);
+    assert_eq!(cpu.registers[0], 5);
+    assert_eq!(cpu.pc, 6);
+}
+
+#[test]
+fn test_set_next_word_indirect() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0xa021,            // SET B, 7
+             0x07c1, 0x1000,    // SET [0x1000], B
+             0x7801, 0x1000     // SET A, [0x1000]
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(0x1000), 7);
+    assert_eq!(cpu.registers[0], 7);
+    assert_eq!(cpu.pc, 5);
+}
+
+#[test]
+fn test_set_peek() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8b01,    // SET PUSH, 1
+             0x9b21,    // SET PEEK, 5
+             0x6401     // SET A, PEEK
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(0xffff), 5);
+    assert_eq!(cpu.registers[0], 5);
+    assert_eq!(cpu.sp, 0xffff);
+    assert_eq!(cpu.pc, 3);
+}
+
+#[test]
+fn test_set_pick() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8b01,            // SET PUSH, 1
+             0x8f01,            // SET PUSH, 2
+             0x9f41, 0x0001,    // SET PICK 1, 6
+             0x6801, 0x0001     // SET A, PICK 1
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(0xffff), 6);
+    assert_eq!(cpu.memory.get(0xfffe), 2);
+    assert_eq!(cpu.registers[0], 6);
+    assert_eq!(cpu.sp, 0xfffe);
+    assert_eq!(cpu.pc, 6);
+}
+
+#[test]
+fn test_set_sp() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x7f61, 0x1000,    // SET SP, 0x1000
+             0xa301             // SET PUSH, 7
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(0x0fff), 7);
+    assert_eq!(cpu.sp, 0x0fff);
+    assert_eq!(cpu.pc, 3);
+}
+
+#[test]
+fn test_set_pc() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x9381,    // SET PC, 3
+             0x8801,    // SET A, 1
+             0x8801,    // SET A, 1
+             0x8c21     // SET B, 2
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[0], 0);
+    assert_eq!(cpu.registers[1], 2);
+    assert_eq!(cpu.pc, 4);
+}
+
+#[test]
+fn test_set_ex() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x7fa1, 0x1234,    // SET EX, 0x1234
+             0x7401             // SET A, EX
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[0], 0x1234);
+    assert_eq!(cpu.ex, 0x1234);
+    assert_eq!(cpu.pc, 3);
+}
+
+#[test]
+fn test_set_literal() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x9be1, 0x0003,    // SET 3, 5
+             0x8c21             // SET B, 2
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(1), 3);
+    assert_eq!(cpu.registers[1], 2);
+    assert_eq!(cpu.pc, 3);
+}
+
+#[test]
+fn test_add_register_next_word() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x7c21, 0x1000,    // SET B, 0x1000
+             0x9a21, 0x0010,    // SET [B+0x10], 5
+             0x9001,            // SET A, 3
+             0x0222, 0x0010     // ADD [B+0x10], A
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(0x1010), 8);
+    assert_eq!(cpu.pc, 7);
+}
+
+#[test]
+fn test_add_pick() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8b01,            // SET PUSH, 1
+             0x8f01,            // SET PUSH, 2
+             0x9342, 0x0001     // ADD PICK 1, 3
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(0xffff), 4);
+    assert_eq!(cpu.memory.get(0xfffe), 2);
+    assert_eq!(cpu.sp, 0xfffe);
+    assert_eq!(cpu.pc, 4);
+}
+
+#[test]
+fn test_sub_next_word_indirect() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x7fc1, 0x0005, 0x1000,    // SET [0x1000], 5
+             0x8bc3, 0x1000             // SUB [0x1000], 1
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(0x1000), 4);
+    assert_eq!(cpu.pc, 5);
+    assert_eq!(cpu.ex, 0);
+}
+
+#[test]
+fn test_cycles() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0xfc01,            // SET A, 30            1
+             0x7c21, 0x001f,    // SET B, 31            2
+             0x0022,            // ADD B, A             2
+             0x9a21, 0x0004,    // SET [B+4], 5         2
+             0x8c06,            // DIV A, 2             3
+             0x7d00, 0x0008     // INT 8                5
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.cycles(), 15);
+}
+
+#[test]
+fn test_cycles_failed_if() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8801,    // SET A, 1     1
+             0x8812,    // IFE A, 1     2
+             0x8821,    // SET B, 1     1
+             0x8c12,    // IFE A, 2     3
+             0x8c21     // SET B, 2     skipped
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[1], 1);
+    assert_eq!(cpu.cycles(), 7);
+}
+
+#[test]
+fn test_run_for_cycles() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8801,    // SET A, 1
+             0x8802,    // ADD A, 1
+             0x8f83     // SUB PC, 2
+    ]);
+    assert_eq!(cpu.run_for_cycles(2), Ok(StopReason::Cycles));
+    assert_eq!(cpu.registers[0], 2);
+    assert_eq!(cpu.cycles(), 3);
+    assert_eq!(cpu.run_for_cycles(4), Ok(StopReason::Cycles));
+    assert_eq!(cpu.registers[0], 3);
+    assert_eq!(cpu.cycles(), 7);
+}
+
+#[test]
+fn test_run_for_duration() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8801,    // SET A, 1
+             0x8802,    // ADD A, 1
+             0x8f83     // SUB PC, 2
+    ]);
+    assert_eq!(cpu.run_for_duration(Duration::new(0, 30_000)), Ok(StopReason::Cycles));
+    assert_eq!(cpu.registers[0], 2);
+    assert_eq!(cpu.cycles(), 3);
+}
+
+#[test]
+fn test_run_for_instructions() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8801,    // SET A, 1
+             0x8802,    // ADD A, 1
+             0x8f83     // SUB PC, 2
+    ]);
+    assert_eq!(cpu.run_for_instructions(3), Ok(StopReason::Instructions));
+    assert_eq!(cpu.registers[0], 2);
+    assert_eq!(cpu.pc, 1);
+}
+
+#[test]
+fn test_run_until() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8801,    // SET A, 1
+             0x8802,    // ADD A, 1
+             0x8f83     // SUB PC, 2
+    ]);
+    assert_eq!(cpu.run_until(|cpu| cpu.a() == 10), Ok(StopReason::Condition));
+    assert_eq!(cpu.registers[0], 10);
+    assert_eq!(cpu.pc, 2);
+}
+
+#[test]
+fn test_if_skip_next_words() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8801,                    // SET A, 1
+             0x8c12,                    // IFE A, 2
+             0x7fc1, 0x0005, 0x1000,    // SET [0x1000], 5
+             0x8c21                     // SET B, 2
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.memory.get(0x1000), 0);
+    assert_eq!(cpu.registers[1], 2);
+    assert_eq!(cpu.pc, 6);
+    assert_eq!(cpu.cycles(), 5);
+}
+
+#[test]
+fn test_if_chained() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8801,            // SET A, 1
+             0x8c12,            // IFE A, 2
+             0x7c12, 0x0001,    // IFE A, 1
+             0x8c21,            // SET B, 2
+             0x9041             // SET C, 3
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[1], 0);
+    assert_eq!(cpu.registers[2], 3);
+    assert_eq!(cpu.pc, 6);
+    assert_eq!(cpu.cycles(), 6);
+}
+
+#[test]
+fn test_iaq() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0xa140,    // IAS 7
+             0x8980,    // IAQ 1
+             0x9900,    // INT 5
+             0x8821,    // SET B, 1
+             0x8580,    // IAQ 0
+             0x8841,    // SET C, 1
+             0x8881,    // SET Y, 1
+             0x0061     // SET X, A
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[0], 5);
+    assert_eq!(cpu.registers[1], 1);
+    assert_eq!(cpu.registers[2], 0);
+    assert_eq!(cpu.registers[3], 5);
+    assert_eq!(cpu.registers[4], 0);
+    assert_eq!(cpu.memory.get(0xffff), 5);
+    assert_eq!(cpu.queued_interrupts(), 0);
+    assert!(cpu.is_queueing());
+    assert_eq!(cpu.pc, 8);
+}
+
+#[test]
+fn test_interrupt_queue() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x9540,    // IAS 4
+             0x8822,    // ADD B, 1
+             0x8822,    // ADD B, 1
+             0xab81,    // SET PC, 9
+             0x0062,    // ADD X, A
+             0x8560     // RFI 0
+    ]);
+    cpu.run_step().unwrap();
+    cpu.interrupt(1);
+    cpu.interrupt(2);
+    assert_eq!(cpu.queued_interrupts(), 2);
+    cpu.run_step().unwrap();
+    assert_eq!(cpu.registers[0], 1);
+    assert_eq!(cpu.queued_interrupts(), 1);
+    assert!(cpu.is_queueing());
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[0], 0);
+    assert_eq!(cpu.registers[1], 2);
+    assert_eq!(cpu.registers[3], 3);
+    assert_eq!(cpu.queued_interrupts(), 0);
+    assert!(!cpu.is_queueing());
+    assert_eq!(cpu.sp, 0);
+    assert_eq!(cpu.pc, 9);
+}
+
+#[test]
+fn test_interrupt_without_ia() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8821,    // SET B, 1
+             0x8c21     // SET B, 2
+    ]);
+    cpu.interrupt(1);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[0], 0);
+    assert_eq!(cpu.registers[1], 2);
+    assert_eq!(cpu.queued_interrupts(), 0);
+    assert_eq!(cpu.sp, 0);
+}
+
+#[test]
+fn test_on_fire() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8980,    // IAQ 1
+             0x8821     // SET B, 1
+    ]);
+    cpu.run_step().unwrap();
+    for n in 0..MAX_QUEUED_INTERRUPTS {
+        cpu.interrupt(n as u16);
+    }
+    assert!(!cpu.is_on_fire());
+    cpu.interrupt(0xffff);
+    assert!(cpu.is_on_fire());
+    assert_eq!(cpu.run(), Err(CpuError::OnFire));
+    assert_eq!(cpu.registers[1], 0);
+    assert_eq!(cpu.pc, 1);
+}
+
+#[cfg(test)]
+struct TestDevice {
+    ticks: u64
+}
+
+#[cfg(test)]
+impl Device for TestDevice {
+    fn id(&self) -> u32 { 0x12345678 }
+    fn version(&self) -> u16 { 3 }
+    fn manufacturer(&self) -> u32 { 0x1c6c8b36 }
+
+    fn interrupt(&mut self, state: &mut CpuState) -> u64 {
+        state.registers[1] = state.registers[0] + 1;
+        state.memory.set(0x1000, 0xbeef);
+        2
+    }
+
+    fn tick(&mut self, cycles: u64) -> Option<u16> {
+        self.ticks += cycles;
+        match self.ticks >= 10 {
+            true => {
+                self.ticks = 0;
+                Some(0x42)
+            },
+            false => None
+        }
+    }
+}
+
+#[test]
+fn test_hwn_hwq() {
+    let mut cpu: Cpu = Default::default();
+    cpu.connect(Box::new(TestDevice { ticks: 0 }));
+    cpu.load_program(&[
+             0x1600,    // HWN Z
+             0x8620     // HWQ 0
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[5], 1);
+    assert_eq!(cpu.registers[0], 0x5678);
+    assert_eq!(cpu.registers[1], 0x1234);
+    assert_eq!(cpu.registers[2], 3);
+    assert_eq!(cpu.registers[3], 0x8b36);
+    assert_eq!(cpu.registers[4], 0x1c6c);
+    assert_eq!(cpu.cycles(), 6);
+}
+
+#[test]
+fn test_hwi() {
+    let mut cpu: Cpu = Default::default();
+    cpu.connect(Box::new(TestDevice { ticks: 0 }));
+    cpu.load_program(&[
+             0xa001,    // SET A, 7
+             0x8640,    // HWI 0
+             0x8a40     // HWI 1
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[1], 8);
+    assert_eq!(cpu.memory.get(0x1000), 0xbeef);
+    assert_eq!(cpu.cycles(), 11);
+}
+
+#[test]
+fn test_hardware_interrupt() {
+    let mut cpu: Cpu = Default::default();
+    cpu.connect(Box::new(TestDevice { ticks: 0 }));
+    cpu.load_program(&[
+             0x9540,    // IAS 4
+             0x8822,    // ADD B, 1
+             0x8b81,    // SET PC, 1
+             0x0000,
+             0x0061,    // SET X, A
+             0x9b81     // SET PC, 5
+    ]);
+    assert_eq!(cpu.run_for_cycles(10), Ok(StopReason::Cycles));
+    assert_eq!(cpu.cycles(), 10);
+    assert_eq!(cpu.queued_interrupts(), 1);
+    assert_eq!(cpu.run_for_cycles(2), Ok(StopReason::Halted { pc: 5 }));
+    assert_eq!(cpu.queued_interrupts(), 0);
+    assert_eq!(cpu.registers[0], 0x42);
+    assert_eq!(cpu.registers[1], 3);
+    assert_eq!(cpu.registers[3], 0x42);
+    assert_eq!(cpu.pc, 5);
+}
+
+#[test]
+fn test_illegal_instruction() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8801,    // SET A, 1
+             0x0000,
+             0x8821     // SET B, 1
+    ]);
+    assert_eq!(cpu.run(), Err(CpuError::IllegalInstruction { pc: 1, word: 0 }));
+    assert_eq!(cpu.registers[0], 1);
+    assert_eq!(cpu.registers[1], 0);
+    assert_eq!(cpu.pc, 1);
+}
+
+#[test]
+fn test_halted() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8801,    // SET A, 1
+             0x8b83     // SUB PC, 1
+    ]);
+    assert_eq!(cpu.run_step(), Ok(StepOutcome { cycles: 1, interrupt: None }));
+    assert_eq!(cpu.run_step(), Err(CpuError::Halted { pc: 1 }));
+    assert_eq!(cpu.run(), Ok(StopReason::Halted { pc: 1 }));
+    assert_eq!(cpu.pc, 1);
+}
+
+#[test]
+fn test_halted_set_pc() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8801,            // SET A, 1
+             0x7f81, 0x0001,    // SET PC, 1
+             0x8821             // SET B, 1
+    ]);
+    assert_eq!(cpu.run_for_cycles(1000), Ok(StopReason::Halted { pc: 1 }));
+    assert_eq!(cpu.registers[1], 0);
+    assert_eq!(cpu.cycles(), 3);
+}
+
+#[test]
+fn test_not_halted_by_pop() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x7f01, 0x0005,    // SET PUSH, 5
+             0x7f01, 0x0004,    // SET PUSH, 4
+             0x6381,            // SET PC, POP
+             0x8801             // SET A, 1
+    ]);
+    assert_eq!(cpu.run(), Ok(StopReason::EndOfProgram));
+    assert_eq!(cpu.registers[0], 1);
+    assert_eq!(cpu.pc, 6);
+}
+
+#[test]
+fn test_not_halted_by_sti() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x7cc1, 0x0003,    // SET I, 3
+             0x3b9e,            // STI PC, [I]
+             0x0002,            // jump table: 2, 2, 6
+             0x0002,
+             0x0006,
+             0x8801             // SET A, 1
+    ]);
+    assert_eq!(cpu.run(), Ok(StopReason::EndOfProgram));
+    assert_eq!(cpu.registers[0], 1);
+    assert_eq!(cpu.registers[6], 6);
+}
+
+#[test]
+fn test_not_halted_by_ex() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x8ba1,    // SET EX, 1
+             0x7783,    // SUB PC, EX
+             0x8801     // SET A, 1
+    ]);
+    assert_eq!(cpu.run(), Ok(StopReason::EndOfProgram));
+    assert_eq!(cpu.registers[0], 1);
+    assert_eq!(cpu.ex, 0);
+}
+
+#[test]
+fn test_get_set_reg() {
+    let mut cpu: Cpu = Default::default();
+    cpu.set_reg(Register::J, 7);
+    cpu.set_reg(Register::PC, 0x100);
+    cpu.set_reg(Register::SP, 0x8000);
+    cpu.set_reg(Register::EX, 1);
+    cpu.set_reg(Register::IA, 0x200);
+    assert_eq!(cpu.registers[7], 7);
+    assert_eq!(cpu.get_reg(Register::J), 7);
+    assert_eq!(cpu.get_reg(Register::A), 0);
+    assert_eq!(cpu.get_reg(Register::PC), 0x100);
+    assert_eq!(cpu.get_reg(Register::SP), 0x8000);
+    assert_eq!(cpu.get_reg(Register::EX), 1);
+    assert_eq!(cpu.get_reg(Register::IA), 0x200);
+}
+
+#[test]
+fn test_read_write_memory() {
+    let mut cpu: Cpu = Default::default();
+    cpu.write_memory(0x1000, 0x9401);     // SET A, 4
+    cpu.write_memory(0x9000, 5);
+    cpu.set_reg(Register::PC, 0x1000);
+    cpu.run_step().unwrap();
+    assert_eq!(cpu.get_reg(Register::A), 4);
+    assert_eq!(cpu.get_reg(Register::PC), 0x1001);
+    assert_eq!(cpu.read_memory(0x9000), 5);
+}
+
+#[test]
+fn test_load_program_at() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program_at(0x0000, &[
+             0x7f81, 0x8000     // SET PC, 0x8000
+    ]);
+    cpu.load_program_at(0x8000, &[
+             0x9401             // SET A, 4
+    ]);
+    assert_eq!(cpu.run(), Ok(StopReason::EndOfProgram));
+    assert_eq!(cpu.registers[0], 4);
+    assert_eq!(cpu.pc, 0x8001);
+}
+
+#[test]
+fn test_reload_program() {
+    let mut cpu: Cpu = Default::default();
+    cpu.load_program(&[
+             0x9401,    // SET A, 4
+             0x9821     // SET B, 5
+    ]);
+    cpu.run().unwrap();
+    cpu.clear_memory();
+    cpu.set_reg(Register::PC, 0);
+    cpu.load_program(&[
+             0xa001     // SET A, 7
+    ]);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[0], 7);
+    assert_eq!(cpu.pc, 1);
+}
+
+#[test]
+fn test_load_binary() {
+    let mut cpu: Cpu = Default::default();
+    let image: &[u8] = &[0x94, 0x01, 0x98, 0x21];  // SET A, 4; SET B, 5
+    assert_eq!(cpu.load_binary(0, image, Endianness::Big).unwrap(), 2);
+    cpu.run().unwrap();
+    assert_eq!(cpu.registers[0], 4);
+    assert_eq!(cpu.registers[1], 5);
+}
+
+#[test]
+fn test_debug_info() {
+    let mut cpu: Cpu = Default::default();
+    let err = CpuError::IllegalInstruction { pc: 1, word: 0 };
+    assert_eq!(cpu.describe_error(&err), "illegal instruction 0x0000 at 0x0001");
+    cpu.load_debug_info(&b"line 0000 2 3 5 main.dasm\nlabel 0000 main\n"[..]).unwrap();
+    assert_eq!(cpu.source_location().map(|location| location.to_string()), Some("main.dasm:3:5".to_string()));
+    assert_eq!(cpu.describe_error(&err), "illegal instruction 0x0000 at 0x0001 in main.dasm:3:5 (main+1)");
+    assert_eq!(cpu.describe_error(&CpuError::OnFire), CpuError::OnFire.to_string());
+    assert_eq!(cpu.describe_address(2), "main+2");
+    assert!(cpu.load_debug_info(&b"line"[..]).is_err());
+}
@@ -0,0 +1,169 @@
+#![allow(dead_code)]
+use std::fmt::{self, Display};
+use std::io::{self, BufRead, Write};
+
+/// where in the sources an instruction or data word was written
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceLocation {
+    pub file: Option<String>,   // none for sources not read from a file
+    pub line: usize,            // starting from 1
+    pub column: usize           // starting from 1
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// words assembled from one source line
+#[derive(Debug, PartialEq, Clone)]
+pub struct LineEntry {
+    pub address: u16,
+    pub len: u16,
+    pub location: SourceLocation
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Label {
+    pub name: String,
+    pub address: u16
+}
+
+/// Maps addresses of an assembled program back to its sources, so that
+/// traces, errors and breakpoints can be shown in terms of them.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DebugInfo {
+    pub lines: Vec<LineEntry>,
+    pub labels: Vec<Label>
+}
+
+impl DebugInfo {
+    /// source of the word at address
+    pub fn location(&self, address: u16) -> Option<&SourceLocation> {
+        self.lines.iter()
+            .find(|entry| address.wrapping_sub(entry.address) < entry.len)
+            .map(|entry| &entry.location)
+    }
+
+    /// closest label at or before address, with the distance from it
+    pub fn label(&self, address: u16) -> Option<(&str, u16)> {
+        self.labels.iter()
+            .filter(|label| label.address <= address)
+            .max_by_key(|label| label.address)
+            .map(|label| (&label.name[..], address - label.address))
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.labels.iter().find(|label| label.name == name).map(|label| label.address)
+    }
+
+    /// first address assembled from the line, to put a breakpoint on it
+    pub fn address_of_line(&self, file: Option<&str>, line: usize) -> Option<u16> {
+        self.lines.iter()
+            .find(|entry| entry.location.line == line && entry.location.file.as_ref().map(|f| &f[..]) == file)
+            .map(|entry| entry.address)
+    }
+
+    /// `file:line:column (label+offset)`, or as much of it as is known
+    pub fn describe(&self, address: u16) -> String {
+        let label = self.label(address).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            offset => format!("{}+{}", name, offset)
+        });
+        match (self.location(address), label) {
+            (Some(location), Some(label)) => format!("{} ({})", location, label),
+            (Some(location), None) => location.to_string(),
+            (None, Some(label)) => label,
+            (None, None) => format!("{:#06x}", address)
+        }
+    }
+
+    /// writes the table as text, one entry per line:
+    /// `line address len line column file` and `label address name`
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for entry in &self.lines {
+            let file = entry.location.file.as_ref().map_or("", |file| &file[..]);
+            writeln!(writer, "line {:04x} {} {} {} {}", entry.address, entry.len,
+                     entry.location.line, entry.location.column, file)?;
+        }
+        for label in &self.labels {
+            writeln!(writer, "label {:04x} {}", label.address, label.name)?;
+        }
+        Ok(())
+    }
+
+    /// reads a table written by `write`
+    pub fn read<R: BufRead>(reader: R) -> io::Result<DebugInfo> {
+        let mut info = DebugInfo::default();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid debug info on line {}", n + 1));
+            let fields: Vec<&str> = line.splitn(6, ' ').collect();
+            match fields[0] {
+                "" => {},
+                "line" if fields.len() == 6 => info.lines.push(LineEntry {
+                    address: u16::from_str_radix(fields[1], 16).map_err(|_| invalid())?,
+                    len: fields[2].parse().map_err(|_| invalid())?,
+                    location: SourceLocation {
+                        file: if fields[5].is_empty() { None } else { Some(fields[5].to_string()) },
+                        line: fields[3].parse().map_err(|_| invalid())?,
+                        column: fields[4].parse().map_err(|_| invalid())?
+                    }
+                }),
+                "label" if fields.len() == 3 => info.labels.push(Label {
+                    address: u16::from_str_radix(fields[1], 16).map_err(|_| invalid())?,
+                    name: fields[2].to_string()
+                }),
+                _ => return Err(invalid())
+            }
+        }
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+fn test_info() -> DebugInfo {
+    DebugInfo {
+        lines: vec![
+            LineEntry { address: 0, len: 2, location: SourceLocation { file: Some("main.dasm".to_string()), line: 3, column: 1 } },
+            LineEntry { address: 2, len: 1, location: SourceLocation { file: None, line: 4, column: 7 } }
+        ],
+        labels: vec![
+            Label { name: "main".to_string(), address: 0 },
+            Label { name: "loop".to_string(), address: 2 }
+        ]
+    }
+}
+
+#[test]
+fn test_lookup() {
+    let info = test_info();
+    assert_eq!(info.location(1).map(|location| location.to_string()), Some("main.dasm:3:1".to_string()));
+    assert_eq!(info.location(3), None);
+    assert_eq!(info.label(1), Some(("main", 1)));
+    assert_eq!(info.label(5), Some(("loop", 3)));
+    assert_eq!(info.address_of("loop"), Some(2));
+    assert_eq!(info.address_of_line(Some("main.dasm"), 3), Some(0));
+    assert_eq!(info.address_of_line(None, 4), Some(2));
+    assert_eq!(info.describe(2), "4:7 (loop)");
+    assert_eq!(info.describe(5), "loop+3");
+    assert_eq!(DebugInfo::default().describe(5), "0x0005");
+}
+
+#[test]
+fn test_read_write() {
+    let info = test_info();
+    let mut text = vec![];
+    info.write(&mut text).unwrap();
+    assert_eq!(String::from_utf8(text.clone()).unwrap(), "line 0000 2 3 1 main.dasm\n\
+                                                          line 0002 1 4 7 \n\
+                                                          label 0000 main\n\
+                                                          label 0002 loop\n");
+    assert_eq!(DebugInfo::read(&text[..]).unwrap(), info);
+    let err = DebugInfo::read(&b"label 0000 main\nline 12 x"[..]).unwrap_err();
+    assert_eq!(err.to_string(), "invalid debug info on line 2");
+}
@@ -0,0 +1,34 @@
+#![allow(dead_code)]
+use super::memory::Memory as Memory;
+
+/// Registers and memory of the cpu, as seen by a device handling an interrupt.
+/// Devices may read or modify any of them.
+pub struct CpuState<'a> {
+    pub registers: &'a mut [u16; 8], // A - J
+    pub memory: &'a mut Memory,
+    pub pc: &'a mut u16,
+    pub sp: &'a mut u16,
+    pub ex: &'a mut u16,
+    pub ia: &'a mut u16
+}
+
+/// Hardware connected to the cpu, enumerated with HWN and HWQ
+/// and controlled with HWI.
+pub trait Device {
+    /// 32 bit word identifying the hardware
+    fn id(&self) -> u32;
+
+    fn version(&self) -> u16;
+
+    /// 32 bit word identifying the manufacturer
+    fn manufacturer(&self) -> u32;
+
+    /// handles HWI sent to the device, returns how many additional cycles it took
+    fn interrupt(&mut self, state: &mut CpuState) -> u64;
+
+    /// called after every instruction with the number of cycles it took,
+    /// returns the message of an interrupt the device wants to send to the cpu
+    fn tick(&mut self, _cycles: u64) -> Option<u16> {
+        None
+    }
+}
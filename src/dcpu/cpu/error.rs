@@ -0,0 +1,36 @@
+#![allow(dead_code)]
+use std::error::Error;
+use std::fmt::{self, Display};
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum CpuError {
+    /// word at pc does not encode any instruction
+    IllegalInstruction { pc: u16, word: u16 },
+    /// the interrupt queue overflowed
+    OnFire,
+    /// instruction at pc jumps to itself and nothing can interrupt it
+    Halted { pc: u16 }
+}
+
+impl CpuError {
+    /// address of the instruction the error happened at
+    pub fn pc(&self) -> Option<u16> {
+        match *self {
+            CpuError::IllegalInstruction { pc, .. } | CpuError::Halted { pc } => Some(pc),
+            CpuError::OnFire => None
+        }
+    }
+}
+
+impl Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::IllegalInstruction { pc, word } =>
+                write!(f, "illegal instruction {:#06x} at {:#06x}", word, pc),
+            CpuError::OnFire => write!(f, "cpu is on fire, interrupt queue overflowed"),
+            CpuError::Halted { pc } => write!(f, "cpu halted at {:#06x}", pc)
+        }
+    }
+}
+
+impl Error for CpuError {}
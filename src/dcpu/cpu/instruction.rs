@@ -1,5 +1,5 @@
 #![allow(dead_code)]
-use std::fmt::{self, Formatter, Display};
+use std::fmt::{self, Display};
 
 #[derive(Debug)]
 pub enum Instruction {
@@ -36,10 +36,80 @@ pub enum Instruction {
     IAG(u8),
     IAS(u8),
     RFI(u8),
+    IAQ(u8),
+    HWN(u8),
+    HWQ(u8),
+    HWI(u8),
 
     NULL
 }
 
+impl Instruction {
+    /// base number of cycles it takes to perform the instruction,
+    /// without the cost of looking up its values
+    pub fn cycles(&self) -> u64 {
+        match *self {
+            Instruction::SET(_, _) => 1,
+            Instruction::ADD(_, _) => 2,
+            Instruction::SUB(_, _) => 2,
+            Instruction::MUL(_, _) => 2,
+            Instruction::MLI(_, _) => 2,
+            Instruction::DIV(_, _) => 3,
+            Instruction::DVI(_, _) => 3,
+            Instruction::MOD(_, _) => 3,
+            Instruction::MDI(_, _) => 3,
+            Instruction::AND(_, _) => 1,
+            Instruction::BOR(_, _) => 1,
+            Instruction::XOR(_, _) => 1,
+            Instruction::SHR(_, _) => 1,
+            Instruction::ASR(_, _) => 1,
+            Instruction::SHL(_, _) => 1,
+            Instruction::IFB(_, _) => 2,
+            Instruction::IFC(_, _) => 2,
+            Instruction::IFE(_, _) => 2,
+            Instruction::IFN(_, _) => 2,
+            Instruction::IFG(_, _) => 2,
+            Instruction::IFA(_, _) => 2,
+            Instruction::IFL(_, _) => 2,
+            Instruction::IFU(_, _) => 2,
+            Instruction::ADX(_, _) => 3,
+            Instruction::SBX(_, _) => 3,
+            Instruction::STI(_, _) => 2,
+            Instruction::STD(_, _) => 2,
+
+            Instruction::JSR(_) => 3,
+            Instruction::INT(_) => 4,
+            Instruction::IAG(_) => 1,
+            Instruction::IAS(_) => 1,
+            Instruction::RFI(_) => 3,
+            Instruction::IAQ(_) => 2,
+            Instruction::HWN(_) => 2,
+            Instruction::HWQ(_) => 4,
+            Instruction::HWI(_) => 4,
+
+            Instruction::NULL => 0
+        }
+    }
+
+    pub fn is_conditional(&self) -> bool {
+        matches!(*self, Instruction::IFB(_, _) | Instruction::IFC(_, _) |
+                        Instruction::IFE(_, _) | Instruction::IFN(_, _) |
+                        Instruction::IFG(_, _) | Instruction::IFA(_, _) |
+                        Instruction::IFL(_, _) | Instruction::IFU(_, _))
+    }
+
+    /// true for SET, ADD and SUB into pc that do not pop the stack, when such an
+    /// instruction jumps to itself repeating it changes nothing but pc. ADD and SUB
+    /// also write ex, which is the same every time unless they read it
+    pub fn only_writes_pc(&self) -> bool {
+        match *self {
+            Instruction::SET(a, b) => b == 0x1c && a != 0x18,
+            Instruction::ADD(a, b) | Instruction::SUB(a, b) => b == 0x1c && a != 0x18 && a != 0x1d,
+            _ => false
+        }
+    }
+}
+
 impl Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", *self)
@@ -49,6 +119,7 @@ impl Display for Instruction {
 pub struct InstructionFactory;
 
 impl InstructionFactory {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(word: &u16) -> Instruction {
         let special = InstructionFactory::is_special(word);
         let opcode = InstructionFactory::opcode(word);
@@ -88,10 +159,31 @@ impl InstructionFactory {
             (true, 0x9) => Instruction::IAG(a),
             (true, 0xa) => Instruction::IAS(a),
             (true, 0xb) => Instruction::RFI(a),
+            (true, 0xc) => Instruction::IAQ(a),
+            (true, 0x10) => Instruction::HWN(a),
+            (true, 0x11) => Instruction::HWQ(a),
+            (true, 0x12) => Instruction::HWI(a),
             _ => Instruction::NULL
         }
     }
 
+    /// number of words the instruction starting with `word` occupies,
+    /// including the next words of its values
+    pub fn length(word: &u16) -> u16 {
+        let a = InstructionFactory::value_length(InstructionFactory::a(word));
+        match InstructionFactory::is_special(word) {
+            true => 1 + a,
+            false => 1 + a + InstructionFactory::value_length(InstructionFactory::b(word))
+        }
+    }
+
+    fn value_length(value: u8) -> u16 {
+        match value {
+            0x10 ..= 0x17 | 0x1a | 0x1e | 0x1f => 1,
+            _ => 0
+        }
+    }
+
     fn is_special(word: &u16) -> bool {
         InstructionFactory::get_bits_in_range(word, 0, 5) == 0
     }
@@ -122,3 +214,13 @@ impl InstructionFactory {
     }
 }
 
+
+#[test]
+fn test_length() {
+    assert_eq!(InstructionFactory::length(&0xfc01), 1);     // SET A, 30
+    assert_eq!(InstructionFactory::length(&0x7c21), 2);     // SET B, next word
+    assert_eq!(InstructionFactory::length(&0x7fc1), 3);     // SET [next word], next word
+    assert_eq!(InstructionFactory::length(&0x9a21), 2);     // SET [B+next word], 5
+    assert_eq!(InstructionFactory::length(&0x7d00), 2);     // INT next word
+    assert_eq!(InstructionFactory::length(&0x0420), 1);     // JSR B
+}
@@ -0,0 +1,14 @@
+#![allow(dead_code)]
+
+/// Place an operand refers to, resolved exactly once per instruction.
+/// Resolving consumes next words and moves SP for PUSH / POP, reading
+/// and writing a location afterwards has no side effects.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Location {
+    Register(usize),    // A - J
+    Memory(u16),
+    Sp,
+    Pc,
+    Ex,
+    Literal(u16)        // writes fail silently
+}
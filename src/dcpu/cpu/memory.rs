@@ -1,25 +1,76 @@
 #![allow(dead_code)]
+use std::io::{self, Read};
+
+/// Order of the two bytes of a word in a binary image
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Endianness {
+    Little,
+    Big
+}
+
+/// reads all bytes from `reader` and joins them into words
+pub fn read_words<R: Read>(mut reader: R, endianness: Endianness) -> io::Result<Vec<u16>> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    if bytes.len() % 2 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "binary image has an odd number of bytes"));
+    }
+    let words = bytes.chunks(2)
+        .map(|pair| match endianness {
+            Endianness::Little => (pair[1] as u16) << 8 | pair[0] as u16,
+            Endianness::Big => (pair[0] as u16) << 8 | pair[1] as u16
+        })
+        .collect();
+    Ok(words)
+}
+
 pub struct Memory {
     memory: [u16; 0x10000],
-    loaded: usize             // how many words are loaded at the beginning of the memory
+    loaded: [bool; 0x10000],  // which words were written by a load
+    end: usize                // position right after the last loaded word
 }
 
 impl Default for Memory {
     fn default() -> Memory {
         Memory { 
             memory: [0; 0x10000],
-            loaded: 0
+            loaded: [false; 0x10000],
+            end: 0
         }
     }
 }
 
 impl Memory {
+    /// loads words right after the previously loaded ones
     pub fn load(&mut self, words: &[u16]) {
-        let len = words.len();
-        for n in self.loaded..(self.loaded+len) {
-            self.memory[n] = words[n - self.loaded]; 
+        let end = self.end as u16;
+        self.load_at(end, words);
+    }
+
+    /// loads words starting at `address`, wrapping around the end of memory
+    pub fn load_at(&mut self, address: u16, words: &[u16]) {
+        let mut pos = address as usize;
+        for word in words {
+            self.memory[pos] = *word;
+            self.loaded[pos] = true;
+            pos = (pos + 1) & 0xffff;
         }
-        self.loaded += len;
+        self.end = address as usize + words.len();
+    }
+
+    /// loads a binary image read from `reader` starting at `address`,
+    /// returns the number of loaded words
+    pub fn load_binary<R: Read>(&mut self, address: u16, reader: R, endianness: Endianness) -> io::Result<usize> {
+        let words = read_words(reader, endianness)?;
+        self.load_at(address, &words);
+        Ok(words.len())
+    }
+
+    /// zeroes the whole memory and forgets what was loaded
+    pub fn clear(&mut self) {
+        self.memory = [0; 0x10000];
+        self.loaded = [false; 0x10000];
+        self.end = 0;
     }
 
     pub fn get(&self, pos: usize) -> u16 {
@@ -31,7 +82,60 @@ impl Memory {
     }
 
     pub fn has_word_at(&self, pos: usize) -> bool {
-        pos < self.loaded
+        self.loaded[pos]
     }
 }
 
+#[test]
+fn test_load() {
+    let mut memory: Memory = Default::default();
+    memory.load(&[1, 2]);
+    memory.load(&[3]);
+    assert_eq!(memory.get(0), 1);
+    assert_eq!(memory.get(2), 3);
+    assert!(memory.has_word_at(2));
+    assert!(!memory.has_word_at(3));
+}
+
+#[test]
+fn test_load_at() {
+    let mut memory: Memory = Default::default();
+    memory.load_at(0x8000, &[1, 2]);
+    memory.load_at(0xffff, &[3, 4]);
+    assert_eq!(memory.get(0x8001), 2);
+    assert_eq!(memory.get(0xffff), 3);
+    assert_eq!(memory.get(0), 4);
+    assert!(!memory.has_word_at(0x7fff));
+    assert!(memory.has_word_at(0x8000));
+    assert!(memory.has_word_at(0));
+}
+
+#[test]
+fn test_clear() {
+    let mut memory: Memory = Default::default();
+    memory.load(&[1, 2]);
+    memory.clear();
+    memory.load(&[3]);
+    assert_eq!(memory.get(0), 3);
+    assert_eq!(memory.get(1), 0);
+    assert!(!memory.has_word_at(1));
+}
+
+#[test]
+fn test_load_binary() {
+    let mut memory: Memory = Default::default();
+    let bytes: &[u8] = &[0x01, 0xfc, 0x21, 0x88];
+    assert_eq!(memory.load_binary(0x10, bytes, Endianness::Little).unwrap(), 2);
+    assert_eq!(memory.get(0x10), 0xfc01);
+    assert_eq!(memory.get(0x11), 0x8821);
+    assert_eq!(memory.load_binary(0x10, bytes, Endianness::Big).unwrap(), 2);
+    assert_eq!(memory.get(0x10), 0x01fc);
+    assert_eq!(memory.get(0x11), 0x2188);
+}
+
+#[test]
+fn test_load_binary_odd() {
+    let mut memory: Memory = Default::default();
+    let bytes: &[u8] = &[0x01, 0xfc, 0x21];
+    assert!(memory.load_binary(0, bytes, Endianness::Little).is_err());
+}
@@ -1,6 +1,11 @@
-mod memory;
+pub mod memory;
 mod instruction;
+mod location;
+pub mod device;
+pub mod error;
+pub mod register;
 pub mod cpu;
+pub mod debug;
 
 #[cfg(test)]
 mod test;
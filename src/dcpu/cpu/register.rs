@@ -0,0 +1,10 @@
+#![allow(dead_code)]
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Register {
+    A, B, C, X, Y, Z, I, J,
+    PC,     // program_counter
+    SP,     // stack_pointer
+    EX,     // extra
+    IA      // interupt address
+}
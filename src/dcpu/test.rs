@@ -1,4 +1,5 @@
 use super::cpu::cpu::Cpu as Cpu;
+use super::cpu::register::Register as Register;
 use super::assembly::parser::Parser as Parser;
 
 #[test]
@@ -6,10 +7,11 @@ fn test_set() {
     let mut parser = Parser::new("SET A, 30\n
                                   SET B, 1");
     let mut cpu = Cpu::new();
-    cpu.load_program(&parser.parse());
-    cpu.run();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
     assert_eq!(cpu.a(), 30);
     assert_eq!(cpu.b(), 1);
+    assert_eq!(cpu.get_reg(Register::PC), 2);
 }
 
 #[test]
@@ -18,8 +20,8 @@ fn test_add() {
                                   SET B, 1\n
                                   ADD B, A");
     let mut cpu = Cpu::new();
-    cpu.load_program(&parser.parse());
-    cpu.run();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
     assert_eq!(cpu.a(), 30);
     assert_eq!(cpu.b(), 31);
 }
@@ -30,8 +32,8 @@ fn test_mul() {
                                   SET B, 2\n
                                   MUL B, A");
     let mut cpu = Cpu::new();
-    cpu.load_program(&parser.parse());
-    cpu.run();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
     assert_eq!(cpu.a(), 30);
     assert_eq!(cpu.b(), 60);
 }
@@ -41,8 +43,8 @@ fn test_div() {
     let mut parser = Parser::new("SET A, 5\n
                                   DIV A, 2");
     let mut cpu = Cpu::new();
-    cpu.load_program(&parser.parse());
-    cpu.run();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
     assert_eq!(cpu.a(), 2);
 }
 
@@ -50,10 +52,191 @@ fn test_div() {
 fn test_sti() {
     let mut parser = Parser::new("STI A, 15");
     let mut cpu = Cpu::new();
-    cpu.load_program(&parser.parse());
-    cpu.run();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
     assert_eq!(cpu.a(), 15);
     assert_eq!(cpu.i(), 1);
     assert_eq!(cpu.j(), 1);
 }
 
+
+#[test]
+fn test_registers() {
+    let mut parser = Parser::new("ADD A, B\n
+                                  SET X, 2");
+    let mut cpu = Cpu::new();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.set_reg(Register::A, 5);
+    cpu.set_reg(Register::B, 7);
+    cpu.run().unwrap();
+    assert_eq!(cpu.get_reg(Register::A), 12);
+    assert_eq!(cpu.get_reg(Register::X), 2);
+    assert_eq!(cpu.get_reg(Register::PC), 2);
+    assert_eq!(cpu.get_reg(Register::EX), 0);
+}
+
+#[test]
+fn test_labels() {
+    let mut parser = Parser::new("SET A, 5\n
+                                  JSR done\n
+                                  SET C, 1\n
+                                  done: SET B, A");
+    let mut cpu = Cpu::new();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
+    assert_eq!(cpu.a(), 5);
+    assert_eq!(cpu.b(), 5);
+    assert_eq!(cpu.c(), 0);
+}
+
+#[test]
+fn test_indirect() {
+    let mut parser = Parser::new("SET A, 0x10\n
+                                  SET [A], 3\n
+                                  SET [A+1], 4\n
+                                  ADD [A+1], [A]\n
+                                  SET B, [0x11]");
+    let mut cpu = Cpu::new();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
+    assert_eq!(cpu.read_memory(0x10), 3);
+    assert_eq!(cpu.read_memory(0x11), 7);
+    assert_eq!(cpu.b(), 7);
+}
+
+#[test]
+fn test_subroutine() {
+    let mut parser = Parser::new("SET A, 2\n
+                                  SET PUSH, 7\n
+                                  JSR double\n
+                                  SET B, A\n
+                                  SET X, POP\n
+                                  SET PC, end\n
+                                  :double ADD A, A\n
+                                  SET PC, POP\n
+                                  :end SET C, 1");
+    let mut cpu = Cpu::new();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
+    assert_eq!(cpu.a(), 4);
+    assert_eq!(cpu.b(), 4);
+    assert_eq!(cpu.c(), 1);
+    assert_eq!(cpu.x(), 7);
+    assert_eq!(cpu.get_reg(Register::SP), 0);
+}
+
+#[test]
+fn test_long_literals() {
+    let mut parser = Parser::new("SET A, 0x1234\n
+                                  SET B, 65535\n
+                                  ADD B, 1000\n
+                                  IFE 0x1234, A\n
+                                  SET C, 1");
+    let mut cpu = Cpu::new();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
+    assert_eq!(cpu.a(), 0x1234);
+    assert_eq!(cpu.b(), 999);
+    assert_eq!(cpu.c(), 1);
+    assert_eq!(cpu.get_reg(Register::EX), 1);
+}
+
+#[test]
+fn test_data() {
+    let mut parser = Parser::new("; sums the words of the table\n
+                                  SET I, table\n
+                                  again: ADD A, [I]\n
+                                  ADD I, 1\n
+                                  IFN [I], 0\n
+                                  SET PC, again\n
+                                  SET PC, end\n
+                                  table: DAT 1, 'a', \"bc\"z ; 1 + 0x61 + 0x62 + 0x63\n
+                                  end: SET B, 1");
+    let mut cpu = Cpu::new();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
+    assert_eq!(cpu.a(), 1 + 0x61 + 0x62 + 0x63);
+    assert_eq!(cpu.b(), 1);
+}
+
+#[test]
+fn test_expressions() {
+    let mut parser = Parser::new("SET I, 0\n
+                                  again: SET A, [table + I]\n
+                                  SET [0x1000 + 0x10 * 2 + I], A\n
+                                  ADD I, 1\n
+                                  IFN I, (end - table)\n
+                                  SET PC, again\n
+                                  SET B, [0x1020 + 1]\n
+                                  SET PC, stop\n
+                                  table: DAT 1 << 4, 0xff & ~0xf, -(3 * 4)\n
+                                  end:\n
+                                  stop: SET C, end - table");
+    let mut cpu = Cpu::new();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
+    assert_eq!(cpu.a(), 0xfff4);
+    assert_eq!(cpu.b(), 0xf0);
+    assert_eq!(cpu.c(), 3);
+    assert_eq!(cpu.read_memory(0x1020), 0x10);
+}
+
+#[test]
+fn test_segments() {
+    let mut parser = Parser::new(".equ STACK, 0x8000\n
+                                  SET SP, STACK\n
+                                  JSR routine\n
+                                  SET PC, end\n
+                                  .org 0x4000\n
+                                  routine: SET A, [value]\n
+                                  SET PC, POP\n
+                                  value: DAT 0x1234\n
+                                  .org 0x20\n
+                                  end: SET B, 1");
+    let mut cpu = Cpu::new();
+    for segment in &parser.parse().unwrap().segments {
+        cpu.load_program_at(segment.origin, &segment.words);
+    }
+    cpu.run().unwrap();
+    assert_eq!(cpu.a(), 0x1234);
+    assert_eq!(cpu.b(), 1);
+    assert_eq!(cpu.get_reg(Register::SP), 0x8000);
+    assert_eq!(cpu.get_reg(Register::PC), 0x21);
+}
+
+#[test]
+fn test_macros() {
+    let mut parser = Parser::new(".macro count_down(register, from)\n
+                                  SET register, from\n
+                                  loop: SUB register, 1\n
+                                  IFN register, 0\n
+                                  SET PC, loop\n
+                                  .endmacro\n
+                                  count_down(A, 5)\n
+                                  count_down(B, 40)\n
+                                  SET C, 1");
+    let mut cpu = Cpu::new();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.run().unwrap();
+    assert_eq!(cpu.a(), 0);
+    assert_eq!(cpu.b(), 0);
+    assert_eq!(cpu.c(), 1);
+}
+
+#[test]
+fn test_debug_info() {
+    let mut parser = Parser::new("SET A, 0\n
+                                  loop: ADD A, 1\n
+                                  IFN A, 3\n
+                                  SET PC, loop\n
+                                  DAT 0");
+    let mut cpu = Cpu::new();
+    cpu.load_program(&parser.parse().unwrap().to_vec());
+    cpu.set_debug_info(parser.debug_info());
+    let breakpoint = cpu.debug_info().unwrap().address_of_line(None, 5).unwrap();
+    cpu.run_until(|cpu| cpu.get_reg(Register::PC) == breakpoint).unwrap();
+    assert_eq!(cpu.a(), 1);
+    assert_eq!(cpu.source_location().unwrap().to_string(), "5:35");
+    let err = cpu.run().unwrap_err();
+    assert_eq!(cpu.describe_error(&err), "illegal instruction 0x0000 at 0x0005 in 9:35 (loop+4)");
+}
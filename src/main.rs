@@ -1,3 +1,4 @@
+#![allow(clippy::upper_case_acronyms, clippy::module_inception)]
 #[macro_use] extern crate matches;
 
 mod dcpu;